@@ -1,16 +1,47 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount, Transfer as SplTransfer};
-use anchor_spl::associated_token::get_associated_token_address;
+use anchor_lang::Discriminator;
+use anchor_spl::token::{self, spl_token::instruction::AuthorityType, Token, TokenAccount};
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token_interface::{self, Mint, TokenInterface, TransferChecked};
+use anchor_spl::associated_token::{
+    get_associated_token_address, get_associated_token_address_with_program_id, AssociatedToken,
+};
 
 declare_id!("AnPhQYFcJEPBG2JTrvaNne85rXufC1Q97bu29YaWvKDs");
 
 const MIN_SUBSCRIPTION_PERIOD: i64 = 86400; // 1 day in seconds
 const MAX_QUALITY_RECORDS: usize = 10;
+const QUALITY_INFO_VERSION: u8 = 2;
+const SUBSCRIPTION_VERSION: u8 = 2;
+const MAX_COUPON_WHITELIST: usize = 10;
+const MAX_VOLUME_DISCOUNT_TIERS: usize = 4;
+const MAX_EVIDENCE_URI_LEN: usize = 200;
+const MAX_PRICE_CAP_POINTS: usize = 5;
+const MAX_WEBHOOK_URL_LEN: usize = 200;
+const MAX_AGENT_ID_LEN: usize = 64;
+const MAX_ENCRYPTED_TARGET_LEN: usize = 256;
+const MAX_RECIPIENTS: usize = 5; // Upper bound on any provider's recipient limit
+const MAX_RECIPIENT_LABEL_LEN: usize = 32;
+const MAX_BUNDLE_PROVIDERS: usize = 5;
+const BUNDLE_ACCOUNTS_PER_PROVIDER: usize = 10;
+const SLASH_ACCOUNTS_PER_SUBSCRIPTION: usize = 3; // Subscription, pass token account, compensated wallet
+const MAX_BLOCKLIST_LEN: usize = 50;
+const MAX_WAITLIST_LEN: usize = 20;
+const WAITLIST_ENTRY_TTL: i64 = 7 * 86400; // Time a queued subscriber has to claim a slot
+const WAITLIST_DEPOSIT: u64 = 10_000_000; // Lamports held per queued subscriber; kept by the provider on expiry
+const NEUTRAL_REPUTATION_SCORE_BPS: u64 = 5_000; // Score used for providers with no ratings yet
+const BPS_DENOMINATOR: u64 = 10_000;
+const CONVERSION_RATE_SCALE: u64 = 1_000_000;
+const EMA_ALPHA_BPS: u64 = 2_000; // Weight of the newest rating in the moving average
+const FULL_WEIGHT_PERIOD: i64 = 30 * 86400; // Ratings covering 30+ days carry full weight
+const RATING_WINDOW: i64 = 7 * 86400; // Ratings accepted up to 7 days after a subscription ends
+const RECIPIENT_UPDATE_COOLDOWN: i64 = 3600; // Minimum time between recipient updates
 
 #[program]
 pub mod subscription_manager {
-    use anchor_spl::token;
-
     use super::*;
 
     pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
@@ -20,178 +51,762 @@ pub mod subscription_manager {
         state.payment_spl_token = ctx.accounts.payment_spl_token.key();
         state.fee_per_day = 1;
         state.collector_fee = 1;
+        state.arbiter = ctx.accounts.owner.key();
         Ok(())
     }
 
     // Subscribe function
     pub fn subscribe(
         ctx: Context<Subscribe>,
-        recipient: String,
+        recipients: Vec<Recipient>,
         end_time: i64,
     ) -> Result<()> {
-        let state = &ctx.accounts.state;
-        let subscriber = &ctx.accounts.subscriber;
-        let data_provider = &ctx.accounts.data_provider;
-        let dp_token_account = &ctx.accounts.nft_token_account;
-        let dp_payment_to_ata = &ctx.accounts.data_provider_payment_ata;
-        let owner_payment_to_ata = &ctx.accounts.owner_payment_ata;
-        let subscriber_payment_from_ata = &ctx.accounts.subscriber_payment_ata;
-
-        // Validate NFT ownership
-        let expected_token_account = get_associated_token_address(
-            &data_provider.key(),
-            &state.nft_program_id,
+        require!(
+            ctx.accounts.beneficiary.key() == ctx.accounts.subscriber.key(),
+            SubscriptionError::InvalidBeneficiary
         );
+        process_subscribe(ctx, recipients, end_time, false)
+    }
+
+    // Subscribe on behalf of the beneficiary, who then owns the subscription
+    // and co-signs to accept it. Refunds go to the payer when
+    // `refund_to_payer` is set
+    pub fn gift_subscription(
+        ctx: Context<Subscribe>,
+        recipients: Vec<Recipient>,
+        end_time: i64,
+        refund_to_payer: bool,
+    ) -> Result<()> {
+        process_subscribe(ctx, recipients, end_time, refund_to_payer)
+    }
 
+    // Subscribes to, or extends, several providers at once with a shared end
+    // time. Remaining accounts are BUNDLE_ACCOUNTS_PER_PROVIDER accounts per
+    // provider: data provider, provider config, quality info, subscription,
+    // subscribers list, provider payment account, provider NFT account,
+    // provider blocklist, provider waitlist and coupon. Pass the program ID
+    // as the coupon to go without one.
+    // Missing quality info, subscription and subscribers list accounts are created.
+    // The recipients go to every provider, so they can't include encrypted
+    // targets, which are sealed to a single provider's key.
+    pub fn subscribe_bundle<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SubscribeBundle<'info>>,
+        recipients: Vec<Recipient>,
+        end_time: i64,
+    ) -> Result<()> {
         require!(
-            dp_token_account.key() == expected_token_account,
-            SubscriptionError::InvalidTokenAccount
+            ctx.remaining_accounts.len().is_multiple_of(BUNDLE_ACCOUNTS_PER_PROVIDER),
+            SubscriptionError::InvalidRemainingAccounts
         );
-
+        let provider_count = ctx.remaining_accounts.len() / BUNDLE_ACCOUNTS_PER_PROVIDER;
         require!(
-            dp_token_account.owner == data_provider.key(),
-            SubscriptionError::InvalidNFTHolder
+            provider_count > 0 && provider_count <= MAX_BUNDLE_PROVIDERS,
+            SubscriptionError::InvalidBundleSize
         );
-
         require!(
-            dp_token_account.amount > 0,
-            SubscriptionError::InvalidNFTHolder
+            !recipients
+                .iter()
+                .any(|recipient| matches!(recipient.target, DeliveryTarget::Encrypted(_))),
+            SubscriptionError::EncryptedTargetInBundle
         );
 
+        let state = &ctx.accounts.state;
+        let subscriber = ctx.accounts.subscriber.to_account_info();
+        let subscriber_key = subscriber.key();
+        let payment_mint = &ctx.accounts.payment_mint;
+        let token_program = &ctx.accounts.token_program;
+        let system_program = ctx.accounts.system_program.to_account_info();
+        let current_time = Clock::get()?.unix_timestamp;
 
-        // Validate subscription period
+        let conversion_rate = payment_conversion_rate(
+            state,
+            &payment_mint.key(),
+            ctx.accounts.accepted_mint.as_ref(),
+        )?;
+        // The bundle discount only applies when buying from several providers
+        let bundle_discount_bps = if provider_count > 1 { state.bundle_discount_bps } else { 0 };
+
+        let mut providers: Vec<Pubkey> = Vec::with_capacity(provider_count);
+        let mut total_provider_fee: u64 = 0;
+        for chunk in ctx.remaining_accounts.chunks(BUNDLE_ACCOUNTS_PER_PROVIDER) {
+            let [
+                data_provider_info,
+                provider_config_info,
+                quality_info_info,
+                subscription_info,
+                subscribers_list_info,
+                provider_payment_info,
+                nft_info,
+                blocklist_info,
+                waitlist_info,
+                coupon_info,
+            ] = chunk else {
+                return err!(SubscriptionError::InvalidRemainingAccounts);
+            };
+            let data_provider_key = data_provider_info.key();
+            require!(
+                !providers.contains(&data_provider_key),
+                SubscriptionError::InvalidRemainingAccounts
+            );
+            providers.push(data_provider_key);
+
+            // Validate NFT ownership
+            let nft_token_account = Account::<TokenAccount>::try_from(nft_info)?;
+            verify_provider_nft(state, &data_provider_key, &nft_token_account)?;
+
+            find_pda_bump(blocklist_info, &[b"blocklist", data_provider_key.as_ref()], ctx.program_id)?;
+            ensure_not_banned(blocklist_info, &[subscriber_key])?;
+
+            // Unregistered providers have no config
+            find_pda_bump(provider_config_info, &[b"provider", data_provider_key.as_ref()], ctx.program_id)?;
+            let mut provider_config = if provider_config_info.data_is_empty() {
+                None
+            } else {
+                Some(Account::<ProviderConfig>::try_from(provider_config_info)?)
+            };
+
+            let provider_payment_ata =
+                InterfaceAccount::<token_interface::TokenAccount>::try_from(provider_payment_info)?;
+            require!(
+                is_associated_token_account(
+                    &provider_payment_ata,
+                    &data_provider_key,
+                    &payment_mint.key(),
+                    &token_program.key(),
+                ),
+                SubscriptionError::InvalidProviderPaymentAccount
+            );
+
+            let quality_info = prepare_quality_info(
+                quality_info_info,
+                &subscriber,
+                &system_program,
+                &data_provider_key,
+                ctx.program_id,
+            )?;
+
+            // Enforce the reputation price cap
+            let fee_per_day = ProviderConfig::settings(provider_config.as_deref()).effective_fee_per_day(state);
+            enforce_price_cap(state, fee_per_day, Some(&quality_info))?;
+
+            // New subscriptions start now; existing ones are extended from their end
+            let subscription_bump = find_pda_bump(
+                subscription_info,
+                &[b"subscription", subscriber_key.as_ref(), data_provider_key.as_ref()],
+                ctx.program_id,
+            )?;
+            let existing = if subscription_info.data_is_empty() {
+                None
+            } else {
+                let subscription = Account::<Subscription>::try_from(subscription_info)?;
+                require!(
+                    subscription.is_held_by(&subscriber_key, None),
+                    SubscriptionError::NotSubscriptionHolder
+                );
+                require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
+                require!(
+                    subscription.payment_mint == payment_mint.key(),
+                    SubscriptionError::PaymentMintMismatch
+                );
+                Some(subscription)
+            };
+            // New and released subscriptions need a free slot
+            if !existing.as_ref().is_some_and(|subscription| subscription.counted) {
+                find_pda_bump(waitlist_info, &[b"waitlist", data_provider_key.as_ref()], ctx.program_id)?;
+                claim_capacity(
+                    ProviderConfig::settings(provider_config.as_deref()),
+                    waitlist_info,
+                    &subscriber,
+                )?;
+            }
+            let period_start = existing
+                .as_ref()
+                .map_or(current_time, |subscription| subscription.end_time.max(current_time));
+            require!(
+                end_time >= period_start + MIN_SUBSCRIPTION_PERIOD,
+                SubscriptionError::PeriodTooShort
+            );
+
+            // Calculate fees
+            let (mut provider_fee, volume_discount_bps) =
+                calculate_provider_fee(state, fee_per_day, end_time - period_start);
+
+            // Apply the provider's coupon before the bundle discount
+            let coupon_id = if coupon_info.key() == crate::ID {
+                None
+            } else {
+                let mut coupon = Account::<Coupon>::try_from(coupon_info)?;
+                require!(
+                    coupon.data_provider == data_provider_key,
+                    SubscriptionError::InvalidCoupon
+                );
+                provider_fee = coupon.redeem(&subscriber_key, current_time, provider_fee)?;
+                coupon.exit(ctx.program_id)?;
+                Some(coupon.coupon_id)
+            };
+            let bundle_discount =
+                (provider_fee as u128 * bundle_discount_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+            let provider_fee = convert_amount(provider_fee - bundle_discount, conversion_rate);
+
+            // Transfer to provider, covering any Token-2022 transfer fee
+            transfer_payment(
+                token_program,
+                &ctx.accounts.subscriber_payment_ata,
+                payment_mint,
+                &provider_payment_ata,
+                subscriber.clone(),
+                gross_up_for_transfer_fee(payment_mint, provider_fee)?,
+            )?;
+            total_provider_fee = total_provider_fee
+                .checked_add(provider_fee)
+                .ok_or(SubscriptionError::FeeOverflow)?;
+
+            match existing {
+                None => {
+                    ProviderConfig::settings(provider_config.as_deref()).check_recipients(&recipients)?;
+                    let mut subscription = Subscription {
+                        version: SUBSCRIPTION_VERSION,
+                        start_time: current_time,
+                        end_time,
+                        recipients: recipients.clone(),
+                        payment_mint: payment_mint.key(),
+                        rated_end_time: 0,
+                        last_recipient_update: 0,
+                        subscriber: subscriber_key,
+                        payer: subscriber_key,
+                        refund_to_payer: false,
+                        pass_mint: None,
+                        paid_amount: provider_fee,
+                        paid_since: current_time,
+                        counted: false,
+                    };
+
+                    // Count the subscription against the provider
+                    subscription.count_against(provider_config.as_deref_mut());
+                    create_program_account(
+                        &subscriber,
+                        subscription_info,
+                        &system_program,
+                        &[
+                            b"subscription",
+                            subscriber_key.as_ref(),
+                            data_provider_key.as_ref(),
+                            &[subscription_bump],
+                        ],
+                        Subscription::space(&subscription.recipients),
+                        &subscription,
+                        ctx.program_id,
+                    )?;
+
+                    // Add to subscribers list
+                    add_subscriber_entry(
+                        subscribers_list_info,
+                        &subscriber,
+                        &system_program,
+                        &data_provider_key,
+                        ctx.program_id,
+                        SubscriberEntry {
+                            subscriber: subscriber_key,
+                            seats: 1,
+                        },
+                    )?;
+
+                    emit!(SubscriptionCreatedEvent {
+                        data_provider: data_provider_key,
+                        subscriber: subscriber_key,
+                        payer: subscriber_key,
+                        pass_mint: None,
+                        recipients: Recipient::summarize(&recipients, subscription_info.key),
+                        end_time,
+                        timestamp: current_time,
+                        coupon_id,
+                        volume_discount_bps,
+                        payment_mint: payment_mint.key(),
+                    });
+                }
+                Some(mut subscription) => {
+                    subscription.record_payment(provider_fee, current_time);
+                    subscription.start_time = period_start;
+                    subscription.end_time = end_time;
+                    subscription.count_against(provider_config.as_deref_mut());
+                    subscription.exit(ctx.program_id)?;
+
+                    emit!(SubscriptionRenewedEvent {
+                        data_provider: data_provider_key,
+                        subscriber: subscriber_key,
+                        new_recipients: Recipient::summarize(&subscription.recipients, subscription_info.key),
+                        new_end_time: end_time,
+                        timestamp: current_time,
+                        coupon_id,
+                        volume_discount_bps,
+                        payment_mint: subscription.payment_mint,
+                    });
+                }
+            }
+            if let Some(provider_config) = &provider_config {
+                provider_config.exit(ctx.program_id)?;
+            }
+        }
+
+        // Collector fee is charged per provider, in one transfer
+        let collector_fee = convert_amount(state.collector_fee, conversion_rate)
+            .checked_mul(provider_count as u64)
+            .ok_or(SubscriptionError::FeeOverflow)?;
+        transfer_payment(
+            token_program,
+            &ctx.accounts.subscriber_payment_ata,
+            payment_mint,
+            &ctx.accounts.owner_payment_ata,
+            subscriber,
+            gross_up_for_transfer_fee(payment_mint, collector_fee)?,
+        )?;
+
+        emit!(BundlePurchasedEvent {
+            subscriber: subscriber_key,
+            providers,
+            end_time,
+            timestamp: current_time,
+            bundle_discount_bps,
+            total_provider_fee,
+            collector_fee,
+            payment_mint: payment_mint.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn renew_subscription(
+        ctx: Context<RenewSubscription>,
+        new_recipients: Vec<Recipient>,
+        new_end_time: i64,
+        ratings: QualityRatings,
+    ) -> Result<()> {
+        // Validate quality ratings and delivery target
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        let settings = ProviderConfig::settings(provider_config.as_ref());
+        settings.check_recipients(&new_recipients)?;
+        let subscriber_key = ctx.accounts.subscriber.key();
+        ensure_not_banned(&ctx.accounts.blocklist, &[subscriber_key])?;
+
+        let state = &ctx.accounts.state;
         let current_time = Clock::get()?.unix_timestamp;
-        require!(
-            end_time >= current_time + MIN_SUBSCRIPTION_PERIOD,
-            SubscriptionError::PeriodTooShort
-        );
+        let renewal_time = ctx.accounts.subscription.renewal_start(new_end_time, current_time)?;
+
+        // A released subscription needs a free slot again
+        if !ctx.accounts.subscription.counted {
+            claim_capacity(settings, &ctx.accounts.waitlist, &ctx.accounts.subscriber)?;
+        }
+
+        // Enforce the reputation price cap
+        let fee_per_day = settings.effective_fee_per_day(state);
+        enforce_price_cap(state, fee_per_day, Some(&ctx.accounts.quality_info))?;
 
         // Calculate fees
-        let duration = end_time - current_time;
-        let provider_fee = (duration * state.fee_per_day as i64) / MIN_SUBSCRIPTION_PERIOD;
+        let extended_duration = new_end_time - renewal_time;
+        let (mut additional_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, extended_duration);
 
-        // Transfer Fees
-        let cpi_accounts_to_owner = SplTransfer {
-            from: subscriber_payment_from_ata.to_account_info().clone(),
-            to: owner_payment_to_ata.to_account_info().clone(),
-            authority: subscriber.to_account_info().clone(),
-        };
-        let cpi_accounts_to_provider = SplTransfer {
-            from: subscriber_payment_from_ata.to_account_info().clone(),
-            to: dp_payment_to_ata.to_account_info().clone(),
-            authority: subscriber.to_account_info().clone(),
+        // Apply coupon discount
+        let coupon_id = match ctx.accounts.coupon.as_mut() {
+            Some(coupon) => {
+                additional_fee = coupon.redeem(&subscriber_key, current_time, additional_fee)?;
+                Some(coupon.coupon_id)
+            }
+            None => None,
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
 
-        // Transfer to owner
-        token::transfer(
-            CpiContext::new(cpi_program.clone(), cpi_accounts_to_owner),
-            state.collector_fee,
+        // Convert fees into the subscription's payment mint
+        let conversion_rate = payment_conversion_rate(
+            state,
+            &ctx.accounts.subscription.payment_mint,
+            ctx.accounts.accepted_mint.as_ref(),
         )?;
-        token::transfer(
-            CpiContext::new(cpi_program, cpi_accounts_to_provider),
-            provider_fee as u64,
+        let collector_fee = convert_amount(state.collector_fee, conversion_rate);
+        let additional_fee = convert_amount(additional_fee, conversion_rate);
+
+        // Transfer fees
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.subscriber_payment_ata,
+            &ctx.accounts.payment_mint,
+            &ctx.accounts.owner_payment_ata,
+            ctx.accounts.subscriber.to_account_info(),
+            gross_up_for_transfer_fee(&ctx.accounts.payment_mint, collector_fee)?,
         )?;
 
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.subscriber_payment_ata,
+            &ctx.accounts.payment_mint,
+            &ctx.accounts.data_provider_payment_ata,
+            ctx.accounts.subscriber.to_account_info(),
+            gross_up_for_transfer_fee(&ctx.accounts.payment_mint, additional_fee)?,
+        )?;
 
-        // Create subscription
+        // Store quality rating, unless this period was already rated
         let subscription = &mut ctx.accounts.subscription;
-        subscription.end_time = end_time;
-        subscription.recipient = recipient.clone();
+        subscription.rate_period(&mut ctx.accounts.quality_info, subscriber_key, ratings)?;
 
-        // Add to subscribers list
-        let subscribers_list = &mut ctx.accounts.subscribers_list;
-        subscribers_list.subscribers.push(subscriber.key());
+        // Update subscription
+        let recipient_summaries = Recipient::summarize(&new_recipients, &subscription.key());
+        subscription.extend(additional_fee, renewal_time, new_end_time, new_recipients, current_time);
+        subscription.count_against(provider_config.as_mut());
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+        fit_subscription_account(
+            subscription,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
 
-        emit!(SubscriptionCreatedEvent {
-            data_provider: data_provider.key(),
-            subscriber: subscriber.key(),
-            recipient: recipient.clone(),
-            end_time,
+        emit!(SubscriptionRenewedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: subscriber_key,
+            new_recipients: recipient_summaries,
+            new_end_time,
             timestamp: current_time,
+            coupon_id,
+            volume_discount_bps,
+            payment_mint: subscription.payment_mint,
         });
 
         Ok(())
     }
 
-    pub fn renew_subscription(
-        ctx: Context<RenewSubscription>,
-        new_recipient: String,
+    // Renews a subscription migrated from the original layout, which was
+    // renewed in lamports rather than a payment mint
+    pub fn renew_legacy_subscription(
+        ctx: Context<RenewLegacySubscription>,
+        new_recipients: Vec<Recipient>,
         new_end_time: i64,
-        quality: u8,
+        ratings: QualityRatings,
     ) -> Result<()> {
-        // Validate quality rating
-        require!(quality <= 100, SubscriptionError::QualityOutOfRange);
+        // Validate quality ratings and delivery target
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        let settings = ProviderConfig::settings(provider_config.as_ref());
+        settings.check_recipients(&new_recipients)?;
+        let subscriber_key = ctx.accounts.subscriber.key();
+        ensure_not_banned(&ctx.accounts.blocklist, &[subscriber_key])?;
 
-        let subscription = &mut ctx.accounts.subscription;
         let state = &ctx.accounts.state;
         let current_time = Clock::get()?.unix_timestamp;
+        let renewal_time = ctx.accounts.subscription.renewal_start(new_end_time, current_time)?;
 
-        // Verify subscription exists (non-zero end time indicates existence)
-        require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
-
-        // Calculate renewal time
-        let renewal_time = if subscription.end_time > current_time {
-            subscription.end_time
-        } else {
-            current_time
-        };
+        // A released subscription needs a free slot again
+        if !ctx.accounts.subscription.counted {
+            claim_capacity(settings, &ctx.accounts.waitlist, &ctx.accounts.subscriber)?;
+        }
 
-        // Validate new subscription period
-        require!(
-            new_end_time >= renewal_time + MIN_SUBSCRIPTION_PERIOD,
-            SubscriptionError::PeriodTooShort
-        );
+        // Enforce the reputation price cap
+        let fee_per_day = settings.effective_fee_per_day(state);
+        enforce_price_cap(state, fee_per_day, Some(&ctx.accounts.quality_info))?;
 
-        // Calculate fees
+        // Calculate fees, priced directly in lamports
         let extended_duration = new_end_time - renewal_time;
-        let additional_fee = (extended_duration * state.fee_per_day as i64) / MIN_SUBSCRIPTION_PERIOD;
+        let (mut additional_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, extended_duration);
 
-        // Transfer fees
-        let transfer_to_owner = anchor_lang::system_program::Transfer {
-            from: ctx.accounts.subscriber.to_account_info(),
-            to: ctx.accounts.owner.to_account_info(),
-        };
-        let transfer_to_provider = anchor_lang::system_program::Transfer {
-            from: ctx.accounts.subscriber.to_account_info(),
-            to: ctx.accounts.data_provider.to_account_info(),
+        // Apply coupon discount
+        let coupon_id = match ctx.accounts.coupon.as_mut() {
+            Some(coupon) => {
+                additional_fee = coupon.redeem(&subscriber_key, current_time, additional_fee)?;
+                Some(coupon.coupon_id)
+            }
+            None => None,
         };
 
+        // Transfer fees
         anchor_lang::system_program::transfer(
             CpiContext::new(
                 ctx.accounts.system_program.to_account_info(),
-                transfer_to_owner,
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.subscriber.to_account_info(),
+                    to: ctx.accounts.owner.to_account_info(),
+                },
             ),
             state.collector_fee,
         )?;
-
         anchor_lang::system_program::transfer(
             CpiContext::new(
                 ctx.accounts.system_program.to_account_info(),
-                transfer_to_provider,
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.subscriber.to_account_info(),
+                    to: ctx.accounts.data_provider.to_account_info(),
+                },
             ),
-            additional_fee as u64,
+            additional_fee,
         )?;
 
-        // Store quality rating
-        let current_index = ctx.accounts.quality_info.current_index as usize;
-        let subscriber_key = ctx.accounts.subscriber.key();
-        ctx.accounts.quality_info.qualities[current_index] = QualityRecord {
-            provider: subscriber_key,
-            quality: quality
-        };
-        ctx.accounts.quality_info.current_index = ((current_index as u8) + 1) % MAX_QUALITY_RECORDS as u8;
+        // Store quality rating, unless this period was already rated
+        let subscription = &mut ctx.accounts.subscription;
+        subscription.rate_period(&mut ctx.accounts.quality_info, subscriber_key, ratings)?;
 
         // Update subscription
-        subscription.end_time = new_end_time;
-        subscription.recipient = new_recipient.clone();
+        let recipient_summaries = Recipient::summarize(&new_recipients, &subscription.key());
+        subscription.extend(additional_fee, renewal_time, new_end_time, new_recipients, current_time);
+        subscription.count_against(provider_config.as_mut());
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+        fit_subscription_account(
+            subscription,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
 
         emit!(SubscriptionRenewedEvent {
             data_provider: ctx.accounts.data_provider.key(),
-            subscriber: ctx.accounts.subscriber.key(),
-            new_recipient,
+            subscriber: subscriber_key,
+            new_recipients: recipient_summaries,
             new_end_time,
             timestamp: current_time,
+            coupon_id,
+            volume_discount_bps,
+            payment_mint: subscription.payment_mint,
+        });
+
+        Ok(())
+    }
+
+    // Changes where data is delivered without paying for an extension
+    pub fn update_recipient(
+        ctx: Context<ManageRecipients>,
+        index: u8,
+        new_target: DeliveryTarget,
+    ) -> Result<()> {
+        new_target.validate()?;
+        let provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        ProviderConfig::settings(provider_config.as_ref()).check_delivery_target(&new_target)?;
+
+        let subscription = &mut ctx.accounts.subscription;
+        let subscription_key = subscription.key();
+        let current_time = Clock::get()?.unix_timestamp;
+        subscription.check_recipient_update(current_time)?;
+
+        let recipient = subscription
+            .recipients
+            .get_mut(index as usize)
+            .ok_or(SubscriptionError::RecipientNotFound)?;
+        let recipient_hash = new_target.hash(&subscription_key);
+        let target = new_target.emitted();
+        recipient.target = new_target;
+        let label = recipient.label.clone();
+        subscription.last_recipient_update = current_time;
+        fit_subscription_account(
+            subscription,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        emit!(RecipientUpdatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: ctx.accounts.subscriber.key(),
+            index,
+            label,
+            recipient_hash,
+            target,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn add_recipient(
+        ctx: Context<ManageRecipients>,
+        recipient: Recipient,
+    ) -> Result<()> {
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
+        subscription.check_recipient_update(current_time)?;
+
+        let mut recipients = subscription.recipients.clone();
+        recipients.push(recipient);
+        let provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        ProviderConfig::settings(provider_config.as_ref()).check_recipients(&recipients)?;
+
+        let added = recipients.last().unwrap();
+        emit!(RecipientAddedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: ctx.accounts.subscriber.key(),
+            index: (recipients.len() - 1) as u8,
+            label: added.label.clone(),
+            recipient_hash: added.target.hash(&subscription.key()),
+            target: added.target.emitted(),
+            timestamp: current_time,
+        });
+
+        subscription.recipients = recipients;
+        subscription.last_recipient_update = current_time;
+        fit_subscription_account(
+            subscription,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_recipient(
+        ctx: Context<ManageRecipients>,
+        index: u8,
+    ) -> Result<()> {
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
+        subscription.check_recipient_update(current_time)?;
+
+        require!(
+            (index as usize) < subscription.recipients.len(),
+            SubscriptionError::RecipientNotFound
+        );
+        // A live subscription always has somewhere to deliver to
+        require!(
+            subscription.recipients.len() > 1,
+            SubscriptionError::InvalidRecipientCount
+        );
+
+        let removed = subscription.recipients.remove(index as usize);
+        subscription.last_recipient_update = current_time;
+        fit_subscription_account(
+            subscription,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        emit!(RecipientRemovedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: ctx.accounts.subscriber.key(),
+            index,
+            label: removed.label,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    // Ends a subscription on the provider's side, refunding the unused part of
+    // what the provider was paid
+    pub fn terminate_subscription(
+        ctx: Context<TerminateSubscription>,
+        reason_code: u16,
+    ) -> Result<()> {
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        let refund_recipient = subscription
+            .refund_recipient_for(ctx.accounts.pass_token_account.as_deref())
+            .ok_or(SubscriptionError::InvalidRefundAccount)?;
+
+        // Refund unused time from the provider's payment account, covering
+        // any Token-2022 transfer fee so the full amount arrives
+        let refund = subscription.unused_amount(current_time);
+        if refund > 0 {
+            transfer_payment(
+                &ctx.accounts.token_program,
+                &ctx.accounts.data_provider_payment_ata,
+                &ctx.accounts.payment_mint,
+                &ctx.accounts.refund_ata,
+                ctx.accounts.data_provider.to_account_info(),
+                gross_up_for_transfer_fee(&ctx.accounts.payment_mint, refund)?,
+            )?;
+        }
+
+        // Clear subscription
+        subscription.recipients.clear();
+        subscription.end_time = 0;
+        subscription.paid_amount = 0;
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        subscription.release(provider_config.as_mut())?;
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+
+        emit!(SubscriptionTerminatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: subscription.subscriber,
+            reason_code,
+            refund,
+            refund_recipient,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    // Terminates a subscription migrated from the original layout, refunding
+    // the unused part of its lamport renewals
+    pub fn terminate_legacy_subscription(
+        ctx: Context<TerminateLegacySubscription>,
+        reason_code: u16,
+    ) -> Result<()> {
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
+
+        // Refund unused time from the provider's wallet
+        let refund = subscription.unused_amount(current_time);
+        if refund > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.data_provider.to_account_info(),
+                        to: ctx.accounts.refund_recipient.to_account_info(),
+                    },
+                ),
+                refund,
+            )?;
+        }
+
+        // Clear subscription
+        subscription.recipients.clear();
+        subscription.end_time = 0;
+        subscription.paid_amount = 0;
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        subscription.release(provider_config.as_mut())?;
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+
+        emit!(SubscriptionTerminatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: subscription.subscriber,
+            reason_code,
+            refund,
+            refund_recipient: subscription.refund_recipient(),
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn ban_subscriber(
+        ctx: Context<ManageBlocklist>,
+        subscriber: Pubkey,
+    ) -> Result<()> {
+        let blocklist = &mut ctx.accounts.blocklist;
+        blocklist.data_provider = ctx.accounts.data_provider.key();
+        if !blocklist.banned.contains(&subscriber) {
+            require!(
+                blocklist.banned.len() < MAX_BLOCKLIST_LEN,
+                SubscriptionError::BlocklistFull
+            );
+            blocklist.banned.push(subscriber);
+        }
+
+        emit!(SubscriberBannedEvent {
+            data_provider: blocklist.data_provider,
+            subscriber,
+        });
+
+        Ok(())
+    }
+
+    pub fn unban_subscriber(
+        ctx: Context<ManageBlocklist>,
+        subscriber: Pubkey,
+    ) -> Result<()> {
+        let blocklist = &mut ctx.accounts.blocklist;
+        blocklist.data_provider = ctx.accounts.data_provider.key();
+        blocklist.banned.retain(|banned| *banned != subscriber);
+
+        emit!(SubscriberUnbannedEvent {
+            data_provider: blocklist.data_provider,
+            subscriber,
         });
 
         Ok(())
@@ -199,10 +814,11 @@ pub mod subscription_manager {
 
     pub fn cancel_subscription(
         ctx: Context<CancelSubscription>,
-        quality: u8,
+        ratings: QualityRatings,
+        evidence: Option<RatingEvidenceArgs>,
     ) -> Result<()> {
-        // Validate quality rating
-        require!(quality <= 100, SubscriptionError::QualityOutOfRange);
+        // Validate quality ratings
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
 
         let subscription = &mut ctx.accounts.subscription;
         let current_time = Clock::get()?.unix_timestamp;
@@ -214,18 +830,46 @@ pub mod subscription_manager {
             SubscriptionError::SubscriptionAlreadyEnded
         );
 
-        // Store quality rating
-        let quality_info = &mut ctx.accounts.quality_info;
-        let current_idx = quality_info.current_index as usize;
-        quality_info.qualities[current_idx] = QualityRecord {
-            provider: ctx.accounts.subscriber.key(),
-            quality: quality
-        };
-        quality_info.current_index = (current_idx as u8 + 1) % MAX_QUALITY_RECORDS as u8;
+        // Store quality rating, unless this period was already rated
+        if subscription.can_rate() {
+            let data_provider_key = ctx.accounts.data_provider.key();
+            let subscriber_key = ctx.accounts.subscriber.key();
+            let rating_id = ctx.accounts.quality_info.rating_count;
+            let weight = subscription.period_weight();
+            ctx.accounts.quality_info.record_rating(subscriber_key, ratings, subscription)?;
+            subscription.rated_end_time = subscription.end_time;
+
+            store_rating_evidence(
+                ctx.accounts.evidence.as_mut(),
+                &evidence,
+                data_provider_key,
+                subscriber_key,
+                rating_id,
+            )?;
+
+            emit!(QualityProvidedEvent {
+                data_provider: data_provider_key,
+                subscriber: subscriber_key,
+                ratings,
+                weight,
+                rating_id,
+                content_hash: evidence.as_ref().map(|evidence| evidence.content_hash),
+                evidence_uri: evidence.map(|evidence| evidence.uri),
+            });
+        } else {
+            // Evidence can only be attached to a new rating
+            require!(
+                evidence.is_none() && ctx.accounts.evidence.is_none(),
+                SubscriptionError::AlreadyRated
+            );
+        }
 
         // Clear subscription
-        subscription.recipient = String::new();
+        subscription.recipients.clear();
         subscription.end_time = 0;
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        subscription.release(provider_config.as_mut())?;
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
 
         emit!(SubscriptionCancelledEvent {
             data_provider: ctx.accounts.data_provider.key(),
@@ -237,10 +881,11 @@ pub mod subscription_manager {
 
     pub fn end_subscription(
         ctx: Context<EndSubscription>,
-        quality: u8,
+        ratings: QualityRatings,
+        evidence: Option<RatingEvidenceArgs>,
     ) -> Result<()> {
-        // Validate quality rating
-        require!(quality <= 100, SubscriptionError::QualityOutOfRange);
+        // Validate quality ratings
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
 
         let subscription = &mut ctx.accounts.subscription;
         let current_time = Clock::get()?.unix_timestamp;
@@ -254,18 +899,46 @@ pub mod subscription_manager {
             SubscriptionError::ActiveSubscription
         );
 
-        // Store quality rating
-        let quality_info = &mut ctx.accounts.quality_info;
-        let current_idx = quality_info.current_index as usize;
-        quality_info.qualities[current_idx] = QualityRecord {
-            provider: ctx.accounts.subscriber.key(),
-            quality: quality
-        };
-        quality_info.current_index = (current_idx as u8 + 1) % MAX_QUALITY_RECORDS as u8;
+        // Store quality rating, unless this period was already rated
+        if subscription.can_rate() {
+            let data_provider_key = ctx.accounts.data_provider.key();
+            let subscriber_key = ctx.accounts.subscriber.key();
+            let rating_id = ctx.accounts.quality_info.rating_count;
+            let weight = subscription.period_weight();
+            ctx.accounts.quality_info.record_rating(subscriber_key, ratings, subscription)?;
+            subscription.rated_end_time = subscription.end_time;
+
+            store_rating_evidence(
+                ctx.accounts.evidence.as_mut(),
+                &evidence,
+                data_provider_key,
+                subscriber_key,
+                rating_id,
+            )?;
+
+            emit!(QualityProvidedEvent {
+                data_provider: data_provider_key,
+                subscriber: subscriber_key,
+                ratings,
+                weight,
+                rating_id,
+                content_hash: evidence.as_ref().map(|evidence| evidence.content_hash),
+                evidence_uri: evidence.map(|evidence| evidence.uri),
+            });
+        } else {
+            // Evidence can only be attached to a new rating
+            require!(
+                evidence.is_none() && ctx.accounts.evidence.is_none(),
+                SubscriptionError::AlreadyRated
+            );
+        }
 
         // Clear subscription
-        subscription.recipient = String::new();
+        subscription.recipients.clear();
         subscription.end_time = 0;
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        subscription.release(provider_config.as_mut())?;
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
 
         emit!(SubscriptionEndedEvent {
             data_provider: ctx.accounts.data_provider.key(),
@@ -275,414 +948,4723 @@ pub mod subscription_manager {
         Ok(())
     }
 
-    pub fn set_fee_per_day(
-        ctx: Context<AdminFunction>,
-        new_fee: u64
-    ) -> Result<()> {
-        // Get mutable reference to state account
-        let state = &mut ctx.accounts.state;
+    // Frees the provider slot held by an expired subscription; anyone can
+    // call it. The subscription stays in place so its holder can still rate
+    // the period with end_subscription, or renew.
+    pub fn release_expired_subscription(ctx: Context<ReleaseExpiredSubscription>) -> Result<()> {
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
 
-        // Update the fee
-        state.fee_per_day = new_fee;
+        require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        require!(
+            current_time >= subscription.end_time,
+            SubscriptionError::ActiveSubscription
+        );
+        require!(subscription.counted, SubscriptionError::SubscriptionNotCounted);
 
-        // Emit the event
-        emit!(FeePerDayUpdatedEvent {
-            new_fee_per_day: new_fee,
+        subscription.release(Some(&mut ctx.accounts.provider_config))?;
+
+        emit!(SubscriptionReleasedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: subscription.subscriber,
+            active_subscriptions: ctx.accounts.provider_config.active_subscriptions,
         });
 
         Ok(())
     }
 
-    pub fn set_collector_fee(
-        ctx: Context<AdminFunction>,
-        new_fee: u64
+    // Buys `seats` seats for a team; the admin then assigns them to member
+    // wallets. `group_id` tells apart the admin's groups at the provider
+    pub fn subscribe_group(
+        ctx: Context<SubscribeGroup>,
+        group_id: u64,
+        seats: u32,
+        end_time: i64,
     ) -> Result<()> {
-        // Get mutable reference to state account
-        let state = &mut ctx.accounts.state;
+        require!(seats > 0, SubscriptionError::InvalidSeatCount);
 
-        // Update the fee
-        state.collector_fee = new_fee;
+        let state = &ctx.accounts.state;
+        let admin = &ctx.accounts.admin;
+        let data_provider_key = ctx.accounts.data_provider.key();
+        let payment_mint = &ctx.accounts.payment_mint;
 
-        // Emit the event
-        emit!(CollectorFeeUpdatedEvent {
-            new_collector_fee: new_fee,
-        });
+        // Validate NFT ownership
+        verify_provider_nft(state, &data_provider_key, &ctx.accounts.nft_token_account)?;
+        ensure_not_banned(&ctx.accounts.blocklist, &[admin.key()])?;
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        let settings = ProviderConfig::settings(provider_config.as_ref());
+        claim_capacity(settings, &ctx.accounts.waitlist, admin)?;
 
-        Ok(())
-    }
+        // Validate subscription period
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            end_time >= current_time + MIN_SUBSCRIPTION_PERIOD,
+            SubscriptionError::PeriodTooShort
+        );
 
-    // Helper function to store quality data
-    pub fn store_data_quality(
-        ctx: Context<StoreQuality>,
-        quality: u8
-    ) -> Result<()> {
-        // Validate quality rating
-        require!(quality <= 100, SubscriptionError::QualityOutOfRange);
+        let quality_info = prepare_quality_info(
+            &ctx.accounts.quality_info,
+            &admin.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &data_provider_key,
+            ctx.program_id,
+        )?;
 
-        // Get mutable reference to quality info account
-        let quality_info = &mut ctx.accounts.quality_info;
+        // Enforce the reputation price cap on the per-seat price
+        let fee_per_day = settings.effective_fee_per_day(state);
+        enforce_price_cap(state, fee_per_day, Some(&quality_info))?;
 
-        // Store the current index in a local variable
-        let current_idx = quality_info.current_index as usize;
+        // Calculate fees; the provider is paid per seat per day
+        let duration = end_time - current_time;
+        let (seat_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, duration);
+        let provider_fee = seat_fee
+            .checked_mul(seats as u64)
+            .ok_or(SubscriptionError::FeeOverflow)?;
 
-        // Store the new quality rating
-        quality_info.qualities[current_idx] = QualityRecord {
-            provider: ctx.accounts.subscriber.key(),
-            quality
-        };
+        // Convert fees into the payment mint
+        let conversion_rate = payment_conversion_rate(
+            state,
+            &payment_mint.key(),
+            ctx.accounts.accepted_mint.as_ref(),
+        )?;
+        let collector_fee = convert_amount(state.collector_fee, conversion_rate);
+        let provider_fee = convert_amount(provider_fee, conversion_rate);
 
-        // Update the current index using modulo for circular buffer
-        quality_info.current_index =
-            (current_idx as u8 + 1) % MAX_QUALITY_RECORDS as u8;
+        // Transfer fees
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.admin_payment_ata,
+            payment_mint,
+            &ctx.accounts.owner_payment_ata,
+            admin.to_account_info(),
+            gross_up_for_transfer_fee(payment_mint, collector_fee)?,
+        )?;
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.admin_payment_ata,
+            payment_mint,
+            &ctx.accounts.data_provider_payment_ata,
+            admin.to_account_info(),
+            gross_up_for_transfer_fee(payment_mint, provider_fee)?,
+        )?;
+
+        // Create the group
+        let group = &mut ctx.accounts.group;
+        group.admin = admin.key();
+        group.data_provider = data_provider_key;
+        group.group_id = group_id;
+        group.seats = seats;
+        group.members = 0;
+        group.start_time = current_time;
+        group.end_time = end_time;
+        group.payment_mint = payment_mint.key();
+
+        // The group counts as one subscription
+        if let Some(provider_config) = provider_config.as_mut() {
+            provider_config.active_subscriptions += 1;
+            group.counted = true;
+        }
+        store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+
+        // List the group once, with its seat count
+        add_subscriber_entry(
+            &ctx.accounts.subscribers_list,
+            &admin.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &data_provider_key,
+            ctx.program_id,
+            SubscriberEntry {
+                subscriber: group.key(),
+                seats,
+            },
+        )?;
+
+        emit!(GroupSubscriptionCreatedEvent {
+            data_provider: data_provider_key,
+            group: group.key(),
+            admin: admin.key(),
+            group_id,
+            seats,
+            end_time,
+            timestamp: current_time,
+            volume_discount_bps,
+            payment_mint: payment_mint.key(),
+        });
+
+        Ok(())
+    }
+
+    // Assigns a seat to `member`, delivering to `recipient`
+    pub fn add_member(ctx: Context<AddGroupMember>, recipient: Recipient) -> Result<()> {
+        recipient.validate()?;
+        let provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        ProviderConfig::settings(provider_config.as_ref()).check_delivery_target(&recipient.target)?;
+
+        let group = &mut ctx.accounts.group;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        require!(
+            current_time < group.end_time,
+            SubscriptionError::SubscriptionAlreadyEnded
+        );
+        require!(group.members < group.seats, SubscriptionError::NoSeatsAvailable);
+        ensure_not_banned(&ctx.accounts.blocklist, &[ctx.accounts.member.key()])?;
+
+        group.members += 1;
+
+        let membership = &mut ctx.accounts.membership;
+        membership.group = group.key();
+        membership.member = ctx.accounts.member.key();
+        membership.added_at = current_time;
+        membership.recipient = recipient;
+        membership.rated_end_time = 0;
+
+        emit!(GroupMemberAddedEvent {
+            data_provider: group.data_provider,
+            group: group.key(),
+            member: membership.member,
+            members: group.members,
+            recipient: membership.recipient.summary(&membership.key()),
+        });
+
+        Ok(())
+    }
+
+    // Lets a member change where their seat is delivered
+    pub fn update_member_recipient(
+        ctx: Context<UpdateMemberRecipient>,
+        recipient: Recipient,
+    ) -> Result<()> {
+        recipient.validate()?;
+        let provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        ProviderConfig::settings(provider_config.as_ref()).check_delivery_target(&recipient.target)?;
+
+        let membership = &mut ctx.accounts.membership;
+        membership.recipient = recipient;
+
+        emit!(GroupMemberRecipientUpdatedEvent {
+            data_provider: ctx.accounts.group.data_provider,
+            group: membership.group,
+            member: membership.member,
+            recipient: membership.recipient.summary(&membership.key()),
+        });
+
+        Ok(())
+    }
+
+    // A member rates the group's current period; see
+    // GroupSubscription::check_member_rating
+    pub fn rate_group_subscription(
+        ctx: Context<RateGroupSubscription>,
+        ratings: QualityRatings,
+        evidence: Option<RatingEvidenceArgs>,
+    ) -> Result<()> {
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
+
+        let group = &mut ctx.accounts.group;
+        let membership = &mut ctx.accounts.membership;
+        group.check_member_rating(membership, Clock::get()?.unix_timestamp)?;
+
+        let data_provider_key = group.data_provider;
+        let member_key = ctx.accounts.member.key();
+        let rating_id = ctx.accounts.quality_info.rating_count;
+        let weight = rating_weight(group.start_time, group.end_time);
+        ctx.accounts
+            .quality_info
+            .record_period_rating(member_key, ratings, group.start_time, group.end_time)?;
+        membership.rated_end_time = group.end_time;
+        group.period_ratings += 1;
+
+        store_rating_evidence(
+            ctx.accounts.evidence.as_mut(),
+            &evidence,
+            data_provider_key,
+            member_key,
+            rating_id,
+        )?;
+
+        emit!(QualityProvidedEvent {
+            data_provider: data_provider_key,
+            subscriber: member_key,
+            ratings,
+            weight,
+            rating_id,
+            content_hash: evidence.as_ref().map(|evidence| evidence.content_hash),
+            evidence_uri: evidence.map(|evidence| evidence.uri),
+        });
+
+        Ok(())
+    }
+
+    // Extends a group, or restarts one that has expired or ended, for all of
+    // its seats. Members keep their seats.
+    pub fn renew_group_subscription(
+        ctx: Context<RenewGroupSubscription>,
+        new_end_time: i64,
+    ) -> Result<()> {
+        let state = &ctx.accounts.state;
+        let admin = &ctx.accounts.admin;
+        let data_provider_key = ctx.accounts.data_provider.key();
+        let payment_mint = &ctx.accounts.payment_mint;
+
+        // Validate NFT ownership
+        verify_provider_nft(state, &data_provider_key, &ctx.accounts.nft_token_account)?;
+        ensure_not_banned(&ctx.accounts.blocklist, &[admin.key()])?;
+
+        // Validate subscription period
+        let current_time = Clock::get()?.unix_timestamp;
+        let renewal_time = ctx.accounts.group.end_time.max(current_time);
+        require!(
+            new_end_time >= renewal_time + MIN_SUBSCRIPTION_PERIOD,
+            SubscriptionError::PeriodTooShort
+        );
+
+        // An ended group needs a free slot again
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        let settings = ProviderConfig::settings(provider_config.as_ref());
+        if !ctx.accounts.group.counted {
+            claim_capacity(settings, &ctx.accounts.waitlist, admin)?;
+        }
+
+        let quality_info = prepare_quality_info(
+            &ctx.accounts.quality_info,
+            &admin.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &data_provider_key,
+            ctx.program_id,
+        )?;
+
+        // Enforce the reputation price cap on the per-seat price
+        let fee_per_day = settings.effective_fee_per_day(state);
+        enforce_price_cap(state, fee_per_day, Some(&quality_info))?;
+
+        // Calculate fees; the provider is paid per seat per day
+        let seats = ctx.accounts.group.seats;
+        let duration = new_end_time - renewal_time;
+        let (seat_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, duration);
+        let provider_fee = seat_fee
+            .checked_mul(seats as u64)
+            .ok_or(SubscriptionError::FeeOverflow)?;
+
+        // Convert fees into the payment mint
+        let conversion_rate = payment_conversion_rate(
+            state,
+            &payment_mint.key(),
+            ctx.accounts.accepted_mint.as_ref(),
+        )?;
+        let collector_fee = convert_amount(state.collector_fee, conversion_rate);
+        let provider_fee = convert_amount(provider_fee, conversion_rate);
+
+        // Transfer fees
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.admin_payment_ata,
+            payment_mint,
+            &ctx.accounts.owner_payment_ata,
+            admin.to_account_info(),
+            gross_up_for_transfer_fee(payment_mint, collector_fee)?,
+        )?;
+        transfer_payment(
+            &ctx.accounts.token_program,
+            &ctx.accounts.admin_payment_ata,
+            payment_mint,
+            &ctx.accounts.data_provider_payment_ata,
+            admin.to_account_info(),
+            gross_up_for_transfer_fee(payment_mint, provider_fee)?,
+        )?;
+
+        // Start the new period
+        let group = &mut ctx.accounts.group;
+        group.start_time = renewal_time;
+        group.end_time = new_end_time;
+        group.period_ratings = 0;
+        if !group.counted {
+            if let Some(provider_config) = provider_config.as_mut() {
+                provider_config.active_subscriptions += 1;
+                group.counted = true;
+            }
+            store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+        }
+
+        emit!(GroupSubscriptionRenewedEvent {
+            data_provider: data_provider_key,
+            group: group.key(),
+            seats,
+            new_end_time,
+            timestamp: current_time,
+            volume_discount_bps,
+            payment_mint: payment_mint.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_member(ctx: Context<RemoveGroupMember>) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+        group.members = group.members.saturating_sub(1);
+
+        emit!(GroupMemberRemovedEvent {
+            data_provider: group.data_provider,
+            group: group.key(),
+            member: ctx.accounts.member.key(),
+            members: group.members,
+        });
+
+        Ok(())
+    }
+
+    // Releases an expired group from the provider's active subscriptions;
+    // anyone can call it. The period stays in place so members can still
+    // rate it within RATING_WINDOW, and the admin can renew the group.
+    pub fn end_group_subscription(ctx: Context<EndGroupSubscription>) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        require!(group.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        require!(
+            current_time >= group.end_time,
+            SubscriptionError::ActiveSubscription
+        );
+        require!(group.counted, SubscriptionError::SubscriptionNotCounted);
+
+        let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?
+            .ok_or(SubscriptionError::ProviderConfigMissing)?;
+        provider_config.active_subscriptions = provider_config.active_subscriptions.saturating_sub(1);
+        store_provider_config(&ctx.accounts.provider_config, &Some(provider_config))?;
+        group.counted = false;
+
+        emit!(GroupSubscriptionEndedEvent {
+            data_provider: group.data_provider,
+            group: group.key(),
+        });
+
+        Ok(())
+    }
+
+    pub fn set_fee_per_day(
+        ctx: Context<AdminFunction>,
+        new_fee: u64
+    ) -> Result<()> {
+        // Get mutable reference to state account
+        let state = &mut ctx.accounts.state;
+
+        // Update the fee
+        state.fee_per_day = new_fee;
+
+        // Emit the event
+        emit!(FeePerDayUpdatedEvent {
+            new_fee_per_day: new_fee,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_collector_fee(
+        ctx: Context<AdminFunction>,
+        new_fee: u64
+    ) -> Result<()> {
+        // Get mutable reference to state account
+        let state = &mut ctx.accounts.state;
+
+        // Update the fee
+        state.collector_fee = new_fee;
+
+        // Emit the event
+        emit!(CollectorFeeUpdatedEvent {
+            new_collector_fee: new_fee,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_arbiter(
+        ctx: Context<AdminFunction>,
+        new_arbiter: Pubkey
+    ) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+        state.arbiter = new_arbiter;
+
+        emit!(ArbiterUpdatedEvent {
+            new_arbiter,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_dispute_bond(
+        ctx: Context<AdminFunction>,
+        new_bond: u64
+    ) -> Result<()> {
+        let state = &mut ctx.accounts.state;
+        state.dispute_bond = new_bond;
+
+        emit!(DisputeBondUpdatedEvent {
+            new_dispute_bond: new_bond,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_bundle_discount(
+        ctx: Context<AdminFunction>,
+        discount_bps: u16
+    ) -> Result<()> {
+        require!(
+            discount_bps as u64 <= BPS_DENOMINATOR,
+            SubscriptionError::InvalidBundleDiscount
+        );
+
+        let state = &mut ctx.accounts.state;
+        state.bundle_discount_bps = discount_bps;
+
+        emit!(BundleDiscountUpdatedEvent {
+            new_bundle_discount_bps: discount_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_slashing_params(
+        ctx: Context<AdminFunction>,
+        params: SlashingParams
+    ) -> Result<()> {
+        // The window is checked against the ratings still in the ring buffer
+        require!(
+            params.slash_fraction_bps as u64 <= BPS_DENOMINATOR
+                && params.slash_window <= MAX_QUALITY_RECORDS as u64,
+            SubscriptionError::InvalidSlashingParams
+        );
+
+        let state = &mut ctx.accounts.state;
+        state.slashing = params;
+
+        emit!(SlashingParamsUpdatedEvent {
+            params,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_price_caps(
+        ctx: Context<AdminFunction>,
+        points: Vec<PriceCapPoint>
+    ) -> Result<()> {
+        validate_price_caps(&points)?;
+
+        let state = &mut ctx.accounts.state;
+        state.price_caps = points.clone();
+
+        emit!(PriceCapsUpdatedEvent {
+            points,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_volume_discounts(
+        ctx: Context<AdminFunction>,
+        tiers: Vec<VolumeDiscount>
+    ) -> Result<()> {
+        // Validate the discount table
+        require!(
+            tiers.len() <= MAX_VOLUME_DISCOUNT_TIERS,
+            SubscriptionError::TooManyVolumeDiscountTiers
+        );
+        for (i, tier) in tiers.iter().enumerate() {
+            require!(
+                tier.discount_bps as u64 <= BPS_DENOMINATOR,
+                SubscriptionError::InvalidVolumeDiscount
+            );
+            // Breakpoints must be strictly increasing
+            require!(
+                i == 0 || tier.min_duration > tiers[i - 1].min_duration,
+                SubscriptionError::InvalidVolumeDiscount
+            );
+        }
+
+        // Update the discount table
+        let state = &mut ctx.accounts.state;
+        state.volume_discounts = tiers.clone();
+
+        emit!(VolumeDiscountsUpdatedEvent {
+            tiers,
+        });
+
+        Ok(())
+    }
+
+    pub fn add_accepted_mint(
+        ctx: Context<AddAcceptedMint>,
+        conversion_rate: u64
+    ) -> Result<()> {
+        require!(conversion_rate > 0, SubscriptionError::InvalidConversionRate);
+
+        let accepted_mint = &mut ctx.accounts.accepted_mint;
+        accepted_mint.mint = ctx.accounts.mint.key();
+        accepted_mint.conversion_rate = conversion_rate;
+
+        emit!(AcceptedMintUpdatedEvent {
+            mint: accepted_mint.mint,
+            conversion_rate,
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_accepted_mint(ctx: Context<RemoveAcceptedMint>) -> Result<()> {
+        emit!(AcceptedMintRemovedEvent {
+            mint: ctx.accounts.accepted_mint.mint,
+        });
+
+        Ok(())
+    }
+
+    // Helper function to store quality data
+    pub fn store_data_quality(
+        ctx: Context<StoreQuality>,
+        ratings: QualityRatings,
+        evidence: Option<RatingEvidenceArgs>,
+    ) -> Result<()> {
+        // Validate quality ratings
+        require!(ratings.is_valid(), SubscriptionError::QualityOutOfRange);
+
+        // Verify the rater holds a current or recently ended subscription
+        let subscription = &mut ctx.accounts.subscription;
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(subscription.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        require!(
+            current_time < subscription.end_time + RATING_WINDOW,
+            SubscriptionError::RatingWindowClosed
+        );
+
+        // Allow one rating per subscription period
+        require!(subscription.can_rate(), SubscriptionError::AlreadyRated);
+        subscription.rated_end_time = subscription.end_time;
+
+        // Store the new quality rating, weighted by the length of the rated period
+        let data_provider_key = ctx.accounts.data_provider.key();
+        let subscriber_key = ctx.accounts.subscriber.key();
+        let rating_id = ctx.accounts.quality_info.rating_count;
+        let weight = subscription.period_weight();
+        ctx.accounts.quality_info.record_rating(subscriber_key, ratings, subscription)?;
+
+        // Store the supporting evidence, if any
+        store_rating_evidence(
+            ctx.accounts.evidence.as_mut(),
+            &evidence,
+            data_provider_key,
+            subscriber_key,
+            rating_id,
+        )?;
 
         // Emit the quality provided event
         emit!(QualityProvidedEvent {
-            data_provider: ctx.accounts.data_provider.key(),
-            subscriber: ctx.accounts.subscriber.key(),
-            quality,
+            data_provider: data_provider_key,
+            subscriber: subscriber_key,
+            ratings,
+            weight,
+            rating_id,
+            content_hash: evidence.as_ref().map(|evidence| evidence.content_hash),
+            evidence_uri: evidence.map(|evidence| evidence.uri),
+        });
+
+        Ok(())
+    }
+
+    pub fn dispute_rating(
+        ctx: Context<DisputeRating>,
+        rating_id: u64,
+        response_hash: [u8; 32],
+    ) -> Result<()> {
+        // Only ratings still held in the ring buffer can be disputed
+        let quality_info = &ctx.accounts.quality_info;
+        require!(
+            rating_id < quality_info.rating_count
+                && quality_info.rating_count - rating_id <= MAX_QUALITY_RECORDS as u64,
+            SubscriptionError::RatingNotFound
+        );
+        let record = quality_info.qualities[(rating_id % MAX_QUALITY_RECORDS as u64) as usize].clone();
+        require!(record.rater != Pubkey::default(), SubscriptionError::RatingNotFound);
+
+        // Post the bond into the dispute account
+        let bond = ctx.accounts.state.dispute_bond;
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.data_provider.to_account_info(),
+                    to: ctx.accounts.dispute.to_account_info(),
+                },
+            ),
+            bond,
+        )?;
+
+        let dispute = &mut ctx.accounts.dispute;
+        dispute.data_provider = ctx.accounts.data_provider.key();
+        dispute.rating_id = rating_id;
+        dispute.record = record.clone();
+        dispute.response_hash = response_hash;
+        dispute.bond = bond;
+        dispute.opened_at = Clock::get()?.unix_timestamp;
+
+        emit!(RatingDisputedEvent {
+            data_provider: dispute.data_provider,
+            rating_id,
+            rater: record.rater,
+            response_hash,
+            bond,
+        });
+
+        Ok(())
+    }
+
+    pub fn resolve_dispute(
+        ctx: Context<ResolveDispute>,
+        uphold: bool,
+    ) -> Result<()> {
+        let dispute = &ctx.accounts.dispute;
+        let bond = dispute.bond;
+
+        if uphold {
+            // Rating stands, the bond is forfeited to the treasury
+            ctx.accounts.dispute.sub_lamports(bond)?;
+            ctx.accounts.treasury.add_lamports(bond)?;
+        } else {
+            // Strike the rating; the bond is refunded when the dispute closes
+            ctx.accounts.quality_info.strike_rating(dispute.rating_id, &dispute.record);
+        }
+
+        emit!(DisputeResolvedEvent {
+            data_provider: dispute.data_provider,
+            rating_id: dispute.rating_id,
+            upheld: uphold,
+            bond,
         });
 
         Ok(())
     }
 
-    pub fn get_subscribers(ctx: Context<GetSubscribers>) -> Result<Vec<Pubkey>> {
-        Ok(ctx.accounts.subscribers_list.subscribers.clone())
+    pub fn register_provider(
+        ctx: Context<RegisterProvider>,
+        stake_amount: u64,
+    ) -> Result<()> {
+        require!(
+            stake_amount >= ctx.accounts.state.slashing.min_provider_stake,
+            SubscriptionError::InsufficientStake
+        );
+
+        // Deposit the collateral into the stake vault
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.data_provider.to_account_info(),
+                    to: ctx.accounts.stake_vault.to_account_info(),
+                },
+            ),
+            stake_amount,
+        )?;
+
+        let provider_config = &mut ctx.accounts.provider_config;
+        provider_config.data_provider = ctx.accounts.data_provider.key();
+        provider_config.stake = stake_amount;
+
+        emit!(ProviderRegisteredEvent {
+            data_provider: provider_config.data_provider,
+            stake: stake_amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_provider_fee_per_day(
+        ctx: Context<ProviderFunction>,
+        new_fee: u64,
+    ) -> Result<()> {
+        ctx.accounts.provider_config.fee_per_day = new_fee;
+
+        emit!(ProviderFeePerDayUpdatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            new_fee_per_day: new_fee,
+        });
+
+        Ok(())
+    }
+
+    // Publishes the x25519 key subscribers encrypt their delivery target to
+    pub fn set_provider_encryption_key(
+        ctx: Context<ProviderFunction>,
+        encryption_key: [u8; 32],
+    ) -> Result<()> {
+        ctx.accounts.provider_config.encryption_key = encryption_key;
+
+        emit!(ProviderEncryptionKeyUpdatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            encryption_key,
+        });
+
+        Ok(())
+    }
+
+    // Caps the provider's active subscriptions; 0 removes the cap
+    pub fn set_provider_max_subscribers(
+        ctx: Context<ProviderFunction>,
+        max_subscribers: u32,
+    ) -> Result<()> {
+        ctx.accounts.provider_config.max_subscribers = max_subscribers;
+
+        emit!(ProviderMaxSubscribersUpdatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            max_subscribers,
+        });
+
+        Ok(())
+    }
+
+    // Queues for a slot at a provider that is at capacity, posting a
+    // WAITLIST_DEPOSIT refunded on leaving or claiming a slot. Entries expire
+    // after WAITLIST_ENTRY_TTL; joining evicts expired ones and forfeits
+    // their deposits to the provider.
+    pub fn join_waitlist(ctx: Context<JoinWaitlist>) -> Result<()> {
+        let provider_config = &ctx.accounts.provider_config;
+        require!(
+            provider_config.free_slots() == Some(0),
+            SubscriptionError::ProviderHasCapacity
+        );
+
+        let subscriber_key = ctx.accounts.subscriber.key();
+        ensure_not_banned(&ctx.accounts.blocklist, &[subscriber_key])?;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        // Evict expired entries
+        let data_provider_key = ctx.accounts.data_provider.key();
+        let waitlist = &mut ctx.accounts.waitlist;
+        waitlist.data_provider = data_provider_key;
+        let expired = evict_expired_entries(&mut waitlist.queue, current_time);
+        let forfeited: u64 = expired.iter().map(|entry| entry.deposit).sum();
+        if forfeited > 0 {
+            waitlist.sub_lamports(forfeited)?;
+            ctx.accounts.data_provider.add_lamports(forfeited)?;
+        }
+        for entry in &expired {
+            emit!(WaitlistEntryExpiredEvent {
+                data_provider: data_provider_key,
+                subscriber: entry.subscriber,
+                forfeited_deposit: entry.deposit,
+            });
+        }
+
+        let waitlist = &mut ctx.accounts.waitlist;
+        require!(
+            !waitlist.queue.iter().any(|entry| entry.subscriber == subscriber_key),
+            SubscriptionError::AlreadyWaitlisted
+        );
+        require!(
+            waitlist.queue.len() < MAX_WAITLIST_LEN,
+            SubscriptionError::WaitlistFull
+        );
+        waitlist.queue.push(WaitlistEntry {
+            subscriber: subscriber_key,
+            joined_at: current_time,
+            deposit: WAITLIST_DEPOSIT,
+        });
+        let position = (waitlist.queue.len() - 1) as u32;
+
+        // Hold the deposit
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.subscriber.to_account_info(),
+                    to: ctx.accounts.waitlist.to_account_info(),
+                },
+            ),
+            WAITLIST_DEPOSIT,
+        )?;
+
+        emit!(WaitlistJoinedEvent {
+            data_provider: data_provider_key,
+            subscriber: subscriber_key,
+            position,
+            deposit: WAITLIST_DEPOSIT,
+            expires_at: current_time + WAITLIST_ENTRY_TTL,
+        });
+
+        Ok(())
+    }
+
+    // Leaves the queue and takes back the deposit
+    pub fn leave_waitlist(ctx: Context<LeaveWaitlist>) -> Result<()> {
+        let subscriber_key = ctx.accounts.subscriber.key();
+        let waitlist = &mut ctx.accounts.waitlist;
+        let position = waitlist
+            .queue
+            .iter()
+            .position(|entry| entry.subscriber == subscriber_key)
+            .ok_or(SubscriptionError::NotWaitlisted)?;
+        let entry = waitlist.queue.remove(position);
+        waitlist.sub_lamports(entry.deposit)?;
+        ctx.accounts.subscriber.add_lamports(entry.deposit)?;
+
+        emit!(WaitlistLeftEvent {
+            data_provider: waitlist.data_provider,
+            subscriber: subscriber_key,
+        });
+
+        Ok(())
+    }
+
+    // Sets how many recipients each of the provider's subscriptions may carry
+    pub fn set_provider_max_recipients(
+        ctx: Context<ProviderFunction>,
+        max_recipients: u8,
+    ) -> Result<()> {
+        require!(
+            max_recipients >= 1 && max_recipients as usize <= MAX_RECIPIENTS,
+            SubscriptionError::InvalidRecipientCount
+        );
+        ctx.accounts.provider_config.max_recipients = max_recipients;
+
+        emit!(ProviderMaxRecipientsUpdatedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            max_recipients,
+        });
+
+        Ok(())
+    }
+
+    pub fn request_stake_withdrawal(ctx: Context<ManageStake>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        ctx.accounts.provider_config.withdrawal_requested_at = current_time;
+
+        emit!(StakeWithdrawalRequestedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            available_at: current_time + ctx.accounts.state.slashing.withdrawal_cooldown,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_stake(
+        ctx: Context<ManageStake>,
+        amount: u64,
+    ) -> Result<()> {
+        let provider_config = &mut ctx.accounts.provider_config;
+        let current_time = Clock::get()?.unix_timestamp;
+
+        // Stake stays locked while subscribers depend on it
+        require!(
+            provider_config.active_subscriptions == 0,
+            SubscriptionError::ActiveSubscriptionsExist
+        );
+        require!(
+            provider_config.withdrawal_requested_at > 0
+                && current_time >= provider_config.withdrawal_requested_at
+                    + ctx.accounts.state.slashing.withdrawal_cooldown,
+            SubscriptionError::StakeCooldownActive
+        );
+        require!(amount <= provider_config.stake, SubscriptionError::InsufficientStake);
+
+        provider_config.stake -= amount;
+        provider_config.withdrawal_requested_at = 0;
+        ctx.accounts.stake_vault.sub_lamports(amount)?;
+        ctx.accounts.data_provider.add_lamports(amount)?;
+
+        emit!(StakeWithdrawnEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            amount,
+            remaining_stake: provider_config.stake,
+        });
+
+        Ok(())
+    }
+
+    // Slashes a provider whose reputation stayed below the threshold over the
+    // last `slash_window` ratings. Remaining accounts are (subscription, pass
+    // token account, compensated wallet) triples covering every subscription
+    // or group holding one of the provider's slots. Compensation goes where a
+    // refund would: the current pass holder for subscriptions represented by
+    // a pass, and the admin for groups. Pass the program ID as the pass token
+    // account when there is no pass. Unexpired ones share the slashed stake
+    // pro-rata to their remaining time.
+    pub fn slash_provider(ctx: Context<SlashProvider>) -> Result<()> {
+        let slashing = ctx.accounts.state.slashing;
+        let quality_info = &ctx.accounts.quality_info;
+        let data_provider_key = ctx.accounts.data_provider.key();
+        let current_time = Clock::get()?.unix_timestamp;
+
+        // Verify the provider is slashable
+        require!(
+            slashing.slash_window > 0
+                && quality_info.rating_count - ctx.accounts.provider_config.ratings_at_last_slash
+                    >= slashing.slash_window
+                && quality_info.score_bps() < slashing.threshold_bps
+                && quality_info.stayed_below(slashing.threshold_bps, slashing.slash_window),
+            SubscriptionError::ProviderNotSlashable
+        );
+
+        // Collect the affected active subscriptions
+        require!(
+            ctx.remaining_accounts.len().is_multiple_of(SLASH_ACCOUNTS_PER_SUBSCRIPTION),
+            SubscriptionError::InvalidRemainingAccounts
+        );
+        let mut seen: Vec<Pubkey> = Vec::new();
+        let mut affected: Vec<(&AccountInfo, u64)> = Vec::new();
+        let mut total_remaining: u64 = 0;
+        for accounts in ctx.remaining_accounts.chunks(SLASH_ACCOUNTS_PER_SUBSCRIPTION) {
+            let (subscription_info, pass_token_info, subscriber_info) = (&accounts[0], &accounts[1], &accounts[2]);
+            require!(
+                subscription_info.owner == ctx.program_id
+                    && !seen.contains(subscription_info.key),
+                SubscriptionError::InvalidRemainingAccounts
+            );
+            seen.push(subscription_info.key());
+
+            let data = subscription_info.try_borrow_data()?;
+            let (end_time, counted, expected_key, compensated) =
+                if data.starts_with(&Subscription::DISCRIMINATOR) {
+                    let subscription = Subscription::try_deserialize(&mut &data[..])?;
+                    let (expected_key, _) = Pubkey::find_program_address(
+                        &[b"subscription", subscription.subscriber.as_ref(), data_provider_key.as_ref()],
+                        ctx.program_id,
+                    );
+                    let pass_token_account = if pass_token_info.key() == crate::ID {
+                        None
+                    } else {
+                        require!(
+                            pass_token_info.owner == &token::ID,
+                            SubscriptionError::InvalidRemainingAccounts
+                        );
+                        Some(TokenAccount::try_deserialize(&mut &pass_token_info.try_borrow_data()?[..])?)
+                    };
+                    let compensated = subscription
+                        .refund_recipient_for(pass_token_account.as_ref())
+                        .ok_or(SubscriptionError::InvalidRemainingAccounts)?;
+                    (subscription.end_time, subscription.counted, expected_key, compensated)
+                } else if data.starts_with(&GroupSubscription::DISCRIMINATOR) {
+                    let group = GroupSubscription::try_deserialize(&mut &data[..])?;
+                    let (expected_key, _) = Pubkey::find_program_address(
+                        &[b"group", group.admin.as_ref(), data_provider_key.as_ref(), &group.group_id.to_le_bytes()],
+                        ctx.program_id,
+                    );
+                    (group.end_time, group.counted, expected_key, group.admin)
+                } else {
+                    return err!(SubscriptionError::InvalidRemainingAccounts);
+                };
+            require!(
+                counted
+                    && subscription_info.key() == expected_key
+                    && subscriber_info.key() == compensated,
+                SubscriptionError::InvalidRemainingAccounts
+            );
+            if end_time > current_time {
+                let remaining = (end_time - current_time) as u64;
+                total_remaining += remaining;
+                affected.push((subscriber_info, remaining));
+            }
+        }
+        // Every slot holder must be listed, so the caller can't steer the
+        // whole slash to the subscriptions they picked
+        require!(
+            seen.len() == ctx.accounts.provider_config.active_subscriptions as usize,
+            SubscriptionError::IncompleteSlashCoverage
+        );
+        require!(total_remaining > 0, SubscriptionError::NoAffectedSubscribers);
+
+        // Distribute the slashed stake
+        let slash_amount = (ctx.accounts.provider_config.stake as u128
+            * slashing.slash_fraction_bps as u128
+            / BPS_DENOMINATOR as u128) as u64;
+        let mut distributed: u64 = 0;
+        for (subscriber_info, remaining) in affected.iter() {
+            let share = (slash_amount as u128 * *remaining as u128 / total_remaining as u128) as u64;
+            ctx.accounts.stake_vault.sub_lamports(share)?;
+            subscriber_info.add_lamports(share)?;
+            distributed += share;
+        }
+
+        let provider_config = &mut ctx.accounts.provider_config;
+        provider_config.stake -= distributed;
+        provider_config.ratings_at_last_slash = ctx.accounts.quality_info.rating_count;
+
+        emit!(ProviderSlashedEvent {
+            data_provider: data_provider_key,
+            amount: distributed,
+            subscribers: affected.len() as u32,
+            remaining_stake: provider_config.stake,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_coupon(
+        ctx: Context<CreateCoupon>,
+        coupon_id: u64,
+        discount: CouponDiscount,
+        expires_at: i64,
+        max_redemptions: u32,
+        whitelist: Vec<Pubkey>,
+    ) -> Result<()> {
+        // Validate coupon terms
+        if let CouponDiscount::Percent(percent) = discount {
+            require!(percent <= 100, SubscriptionError::InvalidCouponDiscount);
+        }
+        require!(
+            whitelist.len() <= MAX_COUPON_WHITELIST,
+            SubscriptionError::CouponWhitelistTooLong
+        );
+
+        let coupon = &mut ctx.accounts.coupon;
+        coupon.data_provider = ctx.accounts.data_provider.key();
+        coupon.coupon_id = coupon_id;
+        coupon.discount = discount;
+        coupon.expires_at = expires_at;
+        coupon.max_redemptions = max_redemptions;
+        coupon.redemptions = 0;
+        coupon.whitelist = whitelist;
+
+        emit!(CouponCreatedEvent {
+            data_provider: coupon.data_provider,
+            coupon_id,
+            discount,
+            expires_at,
+            max_redemptions,
+        });
+
+        Ok(())
+    }
+
+    pub fn get_subscribers(ctx: Context<GetSubscribers>) -> Result<Vec<SubscriberEntry>> {
+        let subscribers_list = &ctx.accounts.subscribers_list;
+        if subscribers_list.data_is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(load_subscribers_list(subscribers_list)?.subscribers)
+    }
+
+    pub fn get_provider_reputation(
+        ctx: Context<GetProviderReputation>,
+    ) -> Result<ProviderReputation> {
+        let quality_info = load_quality_info(&ctx.accounts.quality_info)?
+            .ok_or(SubscriptionError::InvalidQualityInfo)?;
+
+        Ok(ProviderReputation {
+            rating_count: quality_info.rating_count,
+            struck_count: quality_info.struck_count,
+            total_weight: quality_info.total_weight,
+            accuracy: quality_info.accuracy,
+            latency: quality_info.latency,
+            uptime: quality_info.uptime,
+            completeness: quality_info.completeness,
+        })
+    }
+
+    pub fn quote_subscription(
+        ctx: Context<QuoteSubscription>,
+        duration: i64,
+    ) -> Result<SubscriptionQuote> {
+        require!(
+            duration >= MIN_SUBSCRIPTION_PERIOD,
+            SubscriptionError::PeriodTooShort
+        );
+
+        let state = &ctx.accounts.state;
+        let provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+        let fee_per_day = ProviderConfig::settings(provider_config.as_ref()).effective_fee_per_day(state);
+        let (provider_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, duration);
+
+        Ok(SubscriptionQuote {
+            duration,
+            fee_per_day,
+            provider_fee,
+            volume_discount_bps,
+            collector_fee: state.collector_fee,
+        })
+    }
+
+    // Idempotent: an existing QualityInfo (e.g. created by `subscribe`) is left untouched
+    pub fn initialize_quality_info(ctx: Context<InitializeQualityInfo>) -> Result<()> {
+        let quality_info = &mut ctx.accounts.quality_info;
+        if quality_info.version == 0 {
+            quality_info.initialize();
+        }
+
+        Ok(())
+    }
+
+    // Converts an original-layout subscriber registry, which listed bare
+    // subscriber keys, to the current layout in place
+    pub fn migrate_subscribers_list(ctx: Context<MigrateSubscribersList>) -> Result<()> {
+        let subscribers_list_account = ctx.accounts.subscribers_list.to_account_info();
+        require!(
+            subscribers_list_account.data_len() == SubscribersListV1::SIZE,
+            SubscriptionError::SubscribersListAlreadyMigrated
+        );
+        let subscribers_list = SubscribersList::from(SubscribersListV1::load(&subscribers_list_account)?);
+
+        // Resize and rewrite the account
+        resize_account(
+            &subscribers_list_account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            8 + SubscribersList::SIZE,
+        )?;
+        subscribers_list.try_serialize(&mut &mut subscribers_list_account.try_borrow_mut_data()?[..])?;
+
+        emit!(SubscribersListMigratedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscribers: subscribers_list.subscribers.len() as u32,
+        });
+
+        Ok(())
+    }
+
+    // Converts a v1 QualityInfo account to the current layout in place
+    pub fn migrate_quality_info(ctx: Context<MigrateQualityInfo>) -> Result<()> {
+        let quality_info_account = ctx.accounts.quality_info.to_account_info();
+        require!(
+            quality_info_account.data_len() == QualityInfoV1::SIZE,
+            SubscriptionError::QualityInfoAlreadyMigrated
+        );
+        let quality_info = QualityInfo::from(QualityInfoV1::load(&quality_info_account)?);
+
+        // Resize and rewrite the account
+        resize_account(
+            &quality_info_account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            QualityInfo::SIZE,
+        )?;
+        quality_info.try_serialize(&mut &mut quality_info_account.try_borrow_mut_data()?[..])?;
+
+        emit!(QualityInfoMigratedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            version: QUALITY_INFO_VERSION,
+        });
+
+        Ok(())
+    }
+
+    // Converts the original State account to the current layout in place.
+    // Settings added since then start out disabled.
+    pub fn migrate_state(ctx: Context<MigrateState>) -> Result<()> {
+        let state_account = ctx.accounts.state.to_account_info();
+        require!(
+            state_account.data_len() < 8 + State::SIZE,
+            SubscriptionError::StateAlreadyMigrated
+        );
+        let legacy = StateV1::load(&state_account)?;
+        require!(
+            legacy.owner == ctx.accounts.owner.key(),
+            SubscriptionError::NotOwner
+        );
+        let state = State::from(legacy);
+
+        // Resize and rewrite the account
+        resize_account(
+            &state_account,
+            &ctx.accounts.owner.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            8 + State::SIZE,
+        )?;
+        state.try_serialize(&mut &mut state_account.try_borrow_mut_data()?[..])?;
+
+        emit!(StateMigratedEvent {
+            owner: state.owner,
+        });
+
+        Ok(())
+    }
+
+    // Converts a subscription in the original layout to the current one in
+    // place. It keeps renewing in lamports, as it did before
+    pub fn migrate_subscription(ctx: Context<MigrateSubscription>) -> Result<()> {
+        let subscription_account = ctx.accounts.subscription.to_account_info();
+        require!(
+            subscription_account.data_len() == SubscriptionV1::SIZE,
+            SubscriptionError::SubscriptionAlreadyMigrated
+        );
+        let current_time = Clock::get()?.unix_timestamp;
+        let subscription = SubscriptionV1::load(&subscription_account)?
+            .migrate(ctx.accounts.subscriber.key(), current_time);
+
+        // Resize and rewrite the account
+        resize_account(
+            &subscription_account,
+            &ctx.accounts.subscriber.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            Subscription::space(&subscription.recipients),
+        )?;
+        subscription.try_serialize(&mut &mut subscription_account.try_borrow_mut_data()?[..])?;
+
+        emit!(SubscriptionMigratedEvent {
+            data_provider: ctx.accounts.data_provider.key(),
+            subscriber: subscription.subscriber,
+            version: SUBSCRIPTION_VERSION,
+        });
+
+        Ok(())
+    }
+
+}
+
+// Account structures
+#[account]
+pub struct State {
+    pub owner: Pubkey,
+    pub nft_program_id: Pubkey,
+    pub payment_spl_token: Pubkey,
+    pub fee_per_day: u64,
+    pub collector_fee: u64,
+    pub volume_discounts: Vec<VolumeDiscount>,
+    pub arbiter: Pubkey,    // Resolves rating disputes
+    pub dispute_bond: u64,  // Lamports a provider posts to dispute a rating
+    pub slashing: SlashingParams,
+    pub price_caps: Vec<PriceCapPoint>, // Reputation score to maximum price per day
+    pub bundle_discount_bps: u16, // Discount on provider fees in multi-provider bundles
+}
+
+impl State {
+    pub const SIZE: usize = 32 + 32 + 32 + // 3 Pubkeys (32 bytes each)
+                           8 + 8 +          // 2 u64s (8 bytes each)
+                           4 + (MAX_VOLUME_DISCOUNT_TIERS * (8 + 2)) + // volume discount tiers
+                           32 + 8 +         // arbiter + dispute bond
+                           SlashingParams::SIZE +
+                           4 + (MAX_PRICE_CAP_POINTS * (8 + 8)) + // price cap curve
+                           2;               // bundle discount
+
+    // Discount in basis points for the highest breakpoint reached by `duration`
+    pub fn volume_discount_bps(&self, duration: i64) -> u16 {
+        self.volume_discounts
+            .iter()
+            .rev()
+            .find(|tier| duration >= tier.min_duration)
+            .map_or(0, |tier| tier.discount_bps)
+    }
+
+    // Maximum price per day for a provider with `score_bps`, or None when no
+    // curve is configured
+    pub fn price_cap(&self, score_bps: u64) -> Option<u64> {
+        self.price_caps
+            .iter()
+            .rev()
+            .find(|point| score_bps >= point.min_score_bps)
+            .map(|point| point.max_fee_per_day)
+    }
+}
+
+// State layout before the fee schedule, dispute, slashing, price cap and
+// bundle settings. Only used to read and migrate old accounts.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct StateV1 {
+    pub owner: Pubkey,
+    pub nft_program_id: Pubkey,
+    pub payment_spl_token: Pubkey,
+    pub fee_per_day: u64,
+    pub collector_fee: u64,
+}
+
+impl StateV1 {
+    pub const SIZE: usize = 32 + 32 + 32 + 8 + 8;
+
+    // The old State::SIZE undercounted the struct, so old accounts may be
+    // shorter than the layout; missing trailing bytes read as zero
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() >= 8 && data[..8] == State::DISCRIMINATOR,
+            SubscriptionError::InvalidState
+        );
+        let mut fields = [0u8; Self::SIZE];
+        let len = (data.len() - 8).min(Self::SIZE);
+        fields[..len].copy_from_slice(&data[8..8 + len]);
+        Ok(Self::deserialize(&mut &fields[..])?)
+    }
+}
+
+impl From<StateV1> for State {
+    fn from(v1: StateV1) -> Self {
+        State {
+            owner: v1.owner,
+            nft_program_id: v1.nft_program_id,
+            payment_spl_token: v1.payment_spl_token,
+            fee_per_day: v1.fee_per_day,
+            collector_fee: v1.collector_fee,
+            volume_discounts: Vec::new(),
+            arbiter: Pubkey::default(),
+            dispute_bond: 0,
+            slashing: SlashingParams::default(),
+            price_caps: Vec::new(),
+            bundle_discount_bps: 0,
+        }
+    }
+}
+
+// Shared by subscribe and gift_subscription; `subscriber` pays and
+// `beneficiary` owns the subscription
+fn process_subscribe(
+    ctx: Context<Subscribe>,
+    recipients: Vec<Recipient>,
+    end_time: i64,
+    refund_to_payer: bool,
+) -> Result<()> {
+    let state = &ctx.accounts.state;
+    let subscriber = &ctx.accounts.subscriber;
+    let beneficiary_key = ctx.accounts.beneficiary.key();
+    let data_provider = &ctx.accounts.data_provider;
+    let dp_token_account = &ctx.accounts.nft_token_account;
+    let dp_payment_to_ata = &ctx.accounts.data_provider_payment_ata;
+    let owner_payment_to_ata = &ctx.accounts.owner_payment_ata;
+    let subscriber_payment_from_ata = &ctx.accounts.subscriber_payment_ata;
+    let payment_mint = &ctx.accounts.payment_mint;
+
+    // Validate NFT ownership
+    verify_provider_nft(state, &data_provider.key(), dp_token_account)?;
+
+    // Banned wallets can neither subscribe nor gift
+    ensure_not_banned(&ctx.accounts.blocklist, &[beneficiary_key, subscriber.key()])?;
+
+    // Take a free slot, or the one reserved for the beneficiary on the waitlist
+    let mut provider_config = load_provider_config(&ctx.accounts.provider_config)?;
+    let settings = ProviderConfig::settings(provider_config.as_ref());
+    claim_capacity(settings, &ctx.accounts.waitlist, &ctx.accounts.beneficiary)?;
+
+    // Validate delivery targets
+    settings.check_recipients(&recipients)?;
+
+    // Validate subscription period
+    let current_time = Clock::get()?.unix_timestamp;
+    require!(
+        end_time >= current_time + MIN_SUBSCRIPTION_PERIOD,
+        SubscriptionError::PeriodTooShort
+    );
+
+    let quality_info = prepare_quality_info(
+        &ctx.accounts.quality_info,
+        &subscriber.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        &data_provider.key(),
+        ctx.program_id,
+    )?;
+
+    // Enforce the reputation price cap
+    let fee_per_day = settings.effective_fee_per_day(state);
+    enforce_price_cap(state, fee_per_day, Some(&quality_info))?;
+
+    // Calculate fees
+    let duration = end_time - current_time;
+    let (mut provider_fee, volume_discount_bps) = calculate_provider_fee(state, fee_per_day, duration);
+
+    // Apply coupon discount
+    let coupon_id = match ctx.accounts.coupon.as_mut() {
+        Some(coupon) => {
+            provider_fee = coupon.redeem(&beneficiary_key, current_time, provider_fee)?;
+            Some(coupon.coupon_id)
+        }
+        None => None,
+    };
+
+    // Convert fees into the payment mint
+    let conversion_rate = payment_conversion_rate(
+        state,
+        &payment_mint.key(),
+        ctx.accounts.accepted_mint.as_ref(),
+    )?;
+    let collector_fee = convert_amount(state.collector_fee, conversion_rate);
+    let provider_fee = convert_amount(provider_fee, conversion_rate);
+
+    // Transfer Fees
+    let token_program = &ctx.accounts.token_program;
+
+    // Transfer to owner, covering any Token-2022 transfer fee
+    transfer_payment(
+        token_program,
+        subscriber_payment_from_ata,
+        payment_mint,
+        owner_payment_to_ata,
+        subscriber.to_account_info(),
+        gross_up_for_transfer_fee(payment_mint, collector_fee)?,
+    )?;
+    // Transfer to provider, covering any Token-2022 transfer fee so the
+    // provider receives the full amount
+    transfer_payment(
+        token_program,
+        subscriber_payment_from_ata,
+        payment_mint,
+        dp_payment_to_ata,
+        subscriber.to_account_info(),
+        gross_up_for_transfer_fee(payment_mint, provider_fee)?,
+    )?;
+
+
+    // Mint the subscription pass, if requested
+    let pass_mint = mint_subscription_pass(&ctx)?;
+
+    // Create subscription
+    let subscription = &mut ctx.accounts.subscription;
+    subscription.version = SUBSCRIPTION_VERSION;
+    subscription.record_payment(provider_fee, current_time);
+    subscription.start_time = current_time;
+    subscription.end_time = end_time;
+    let recipient_summaries = Recipient::summarize(&recipients, &subscription.key());
+    subscription.recipients = recipients;
+    subscription.payment_mint = payment_mint.key();
+    subscription.subscriber = beneficiary_key;
+    subscription.payer = subscriber.key();
+    subscription.refund_to_payer = refund_to_payer;
+    subscription.pass_mint = pass_mint;
+
+    // Count the subscription against the provider
+    subscription.count_against(provider_config.as_mut());
+    store_provider_config(&ctx.accounts.provider_config, &provider_config)?;
+
+    // Add to subscribers list
+    add_subscriber_entry(
+        &ctx.accounts.subscribers_list,
+        &subscriber.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        &data_provider.key(),
+        ctx.program_id,
+        SubscriberEntry {
+            subscriber: beneficiary_key,
+            seats: 1,
+        },
+    )?;
+
+    emit!(SubscriptionCreatedEvent {
+        data_provider: data_provider.key(),
+        subscriber: beneficiary_key,
+        payer: subscriber.key(),
+        pass_mint: subscription.pass_mint,
+        recipients: recipient_summaries,
+        end_time,
+        timestamp: current_time,
+        coupon_id,
+        volume_discount_bps,
+        payment_mint: payment_mint.key(),
+    });
+
+    Ok(())
+}
+
+// Rejects banned wallets. The blocklist is created on the provider's first
+// ban, so an empty account means nobody is banned
+fn ensure_not_banned(blocklist: &AccountInfo, wallets: &[Pubkey]) -> Result<()> {
+    if blocklist.data_is_empty() {
+        return Ok(());
+    }
+    require!(
+        blocklist.owner == &crate::ID,
+        SubscriptionError::InvalidBlocklist
+    );
+    let blocklist = Blocklist::try_deserialize(&mut &blocklist.try_borrow_data()?[..])?;
+    require!(
+        wallets.iter().all(|wallet| !blocklist.banned.contains(wallet)),
+        SubscriptionError::SubscriberBanned
+    );
+    Ok(())
+}
+
+// Fails with ProviderAtCapacity unless a slot is free for `subscriber`.
+// Waitlisted subscribers get freed slots first, in queue order, and leave
+// the queue once they take one. Slots free up when subscriptions end or are
+// released by release_expired_subscription, so lapsed subscriptions don't
+// hold the queue. An empty account means nobody is queued
+fn claim_capacity(
+    provider_config: &ProviderConfig,
+    waitlist: &AccountInfo,
+    subscriber: &AccountInfo,
+) -> Result<()> {
+    let mut queue = if waitlist.data_is_empty() {
+        None
+    } else {
+        require!(
+            waitlist.owner == &crate::ID,
+            SubscriptionError::InvalidWaitlist
+        );
+        Some(Waitlist::try_deserialize(&mut &waitlist.try_borrow_data()?[..])?)
+    };
+    let current_time = Clock::get()?.unix_timestamp;
+
+    if let Some(free_slots) = provider_config.free_slots() {
+        // Everyone still queued ahead of the subscriber needs a slot first;
+        // expired entries no longer hold anyone up
+        let ahead = queue.as_ref().map_or(0, |queue| {
+            waitlist_position(&queue.queue, subscriber.key, current_time)
+        });
+        require!(
+            ahead < free_slots as usize,
+            SubscriptionError::ProviderAtCapacity
+        );
+    }
+
+    // Take the subscriber off the queue and return their deposit
+    let position = queue
+        .as_ref()
+        .and_then(|queue| queue.queue.iter().position(|entry| entry.subscriber == *subscriber.key));
+    if let (Some(queue), Some(position)) = (queue.as_mut(), position) {
+        let entry = queue.queue.remove(position);
+        queue.try_serialize(&mut &mut waitlist.try_borrow_mut_data()?[..])?;
+        waitlist.sub_lamports(entry.deposit)?;
+        subscriber.add_lamports(entry.deposit)?;
+    }
+    Ok(())
+}
+
+// Live entries queued ahead of the subscriber, or all live entries if the
+// subscriber isn't queued
+fn waitlist_position(queue: &[WaitlistEntry], subscriber: &Pubkey, current_time: i64) -> usize {
+    let live: Vec<&WaitlistEntry> = queue.iter().filter(|entry| entry.is_live(current_time)).collect();
+    live.iter()
+        .position(|entry| entry.subscriber == *subscriber)
+        .unwrap_or(live.len())
+}
+
+// Drops expired entries from the queue and returns them
+fn evict_expired_entries(queue: &mut Vec<WaitlistEntry>, current_time: i64) -> Vec<WaitlistEntry> {
+    let (live, expired) = queue
+        .drain(..)
+        .partition(|entry| entry.is_live(current_time));
+    *queue = live;
+    expired
+}
+
+// Checks that `account` is the PDA for `seeds` and returns its bump
+fn find_pda_bump(account: &AccountInfo, seeds: &[&[u8]], program_id: &Pubkey) -> Result<u8> {
+    let (expected, bump) = Pubkey::find_program_address(seeds, program_id);
+    require!(
+        account.key() == expected,
+        SubscriptionError::InvalidRemainingAccounts
+    );
+    Ok(bump)
+}
+
+// Creates a program-owned PDA and writes `value` into it. Like Anchor's
+// `init`, this also works when the address was already credited lamports
+fn create_program_account<'info, T: AccountSerialize>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    signer_seeds: &[&[u8]],
+    space: usize,
+    value: &T,
+    program_id: &Pubkey,
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    let signer = &[signer_seeds];
+    if account.lamports() == 0 {
+        anchor_lang::system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                anchor_lang::system_program::CreateAccount {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+                signer,
+            ),
+            rent,
+            space as u64,
+            program_id,
+        )?;
+    } else {
+        let shortfall = rent.saturating_sub(account.lamports());
+        if shortfall > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    system_program.clone(),
+                    anchor_lang::system_program::Transfer {
+                        from: payer.clone(),
+                        to: account.clone(),
+                    },
+                ),
+                shortfall,
+            )?;
+        }
+        anchor_lang::system_program::allocate(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                anchor_lang::system_program::Allocate {
+                    account_to_allocate: account.clone(),
+                },
+                signer,
+            ),
+            space as u64,
+        )?;
+        anchor_lang::system_program::assign(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                anchor_lang::system_program::Assign {
+                    account_to_assign: account.clone(),
+                },
+                signer,
+            ),
+            program_id,
+        )?;
+    }
+
+    value.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])?;
+    Ok(())
+}
+
+// Resizes a program-owned account, topping up rent from `payer` when it
+// grows and returning the freed rent to `payer` when it shrinks
+fn resize_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    let rent = Rent::get()?;
+    let required_lamports = rent.minimum_balance(new_len);
+    if new_len > account.data_len() {
+        let missing_lamports = required_lamports.saturating_sub(account.lamports());
+        if missing_lamports > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    system_program.clone(),
+                    anchor_lang::system_program::Transfer {
+                        from: payer.clone(),
+                        to: account.clone(),
+                    },
+                ),
+                missing_lamports,
+            )?;
+        }
+    } else {
+        let freed_lamports = rent
+            .minimum_balance(account.data_len())
+            .saturating_sub(required_lamports)
+            .min(account.lamports().saturating_sub(required_lamports));
+        if freed_lamports > 0 {
+            account.sub_lamports(freed_lamports)?;
+            payer.add_lamports(freed_lamports)?;
+        }
+    }
+
+    account.realloc(new_len, true)?;
+    Ok(())
+}
+
+// Checks that the data provider holds the provider NFT
+fn verify_provider_nft(
+    state: &State,
+    data_provider: &Pubkey,
+    nft_token_account: &Account<TokenAccount>,
+) -> Result<()> {
+    let expected_token_account = get_associated_token_address(
+        data_provider,
+        &state.nft_program_id,
+    );
+
+    require!(
+        nft_token_account.key() == expected_token_account,
+        SubscriptionError::InvalidTokenAccount
+    );
+
+    require!(
+        nft_token_account.owner == *data_provider,
+        SubscriptionError::InvalidNFTHolder
+    );
+
+    require!(
+        nft_token_account.amount > 0,
+        SubscriptionError::InvalidNFTHolder
+    );
+
+    Ok(())
+}
+
+// Mints the single pass token to the beneficiary and revokes the mint
+// authority so no second pass can exist. Returns None without pass accounts
+fn mint_subscription_pass(ctx: &Context<Subscribe>) -> Result<Option<Pubkey>> {
+    let accounts = &ctx.accounts;
+    let (pass_mint, pass_token_account, pass_token_program) = match (
+        accounts.pass_mint.as_ref(),
+        accounts.pass_token_account.as_ref(),
+        accounts.pass_token_program.as_ref(),
+    ) {
+        (None, None, _) => return Ok(None),
+        (Some(pass_mint), Some(pass_token_account), Some(pass_token_program)) => {
+            (pass_mint, pass_token_account, pass_token_program)
+        }
+        _ => return err!(SubscriptionError::PassAccountsMismatch),
+    };
+
+    let subscription_key = accounts.subscription.key();
+    let (_, bump) = Pubkey::find_program_address(
+        &[b"pass", subscription_key.as_ref()],
+        ctx.program_id,
+    );
+    let signer_seeds: &[&[&[u8]]] = &[&[b"pass", subscription_key.as_ref(), &[bump]]];
+
+    token::mint_to(
+        CpiContext::new_with_signer(
+            pass_token_program.to_account_info(),
+            token::MintTo {
+                mint: pass_mint.to_account_info(),
+                to: pass_token_account.to_account_info(),
+                authority: pass_mint.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        1,
+    )?;
+    token::set_authority(
+        CpiContext::new_with_signer(
+            pass_token_program.to_account_info(),
+            token::SetAuthority {
+                current_authority: pass_mint.to_account_info(),
+                account_or_mint: pass_mint.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        AuthorityType::MintTokens,
+        None,
+    )?;
+
+    Ok(Some(pass_mint.key()))
+}
+
+// Provider fee for `duration` seconds after the volume discount, along with the
+// discount applied in basis points
+fn calculate_provider_fee(state: &State, fee_per_day: u64, duration: i64) -> (u64, u16) {
+    let base_fee = ((duration * fee_per_day as i64) / MIN_SUBSCRIPTION_PERIOD) as u64;
+    let discount_bps = state.volume_discount_bps(duration);
+    let discount = (base_fee as u128 * discount_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+    (base_fee - discount, discount_bps)
+}
+
+// Checks an admin price cap curve. The curve starts at a score of 0 so every
+// score has a cap, and breakpoints must be strictly increasing.
+fn validate_price_caps(points: &[PriceCapPoint]) -> Result<()> {
+    require!(
+        points.len() <= MAX_PRICE_CAP_POINTS,
+        SubscriptionError::TooManyPriceCapPoints
+    );
+    for (i, point) in points.iter().enumerate() {
+        require!(
+            if i == 0 {
+                point.min_score_bps == 0
+            } else {
+                point.min_score_bps > points[i - 1].min_score_bps
+            },
+            SubscriptionError::InvalidPriceCapCurve
+        );
+    }
+    Ok(())
+}
+
+// Rejects prices above the cap the admin curve allows for the provider's
+// reputation. Providers without ratings are scored as neutral.
+fn enforce_price_cap(
+    state: &State,
+    fee_per_day: u64,
+    quality_info: Option<&QualityInfo>,
+) -> Result<()> {
+    let score_bps = quality_info.map_or(NEUTRAL_REPUTATION_SCORE_BPS, |quality_info| quality_info.score_bps());
+
+    if let Some(max_fee_per_day) = state.price_cap(score_bps) {
+        require!(
+            fee_per_day <= max_fee_per_day,
+            SubscriptionError::PriceAboveReputationCap
+        );
+    }
+
+    Ok(())
+}
+
+// Weight of a rating for a period; periods longer than FULL_WEIGHT_PERIOD
+// count the same as one of that length
+fn rating_weight(period_start: i64, period_end: i64) -> u64 {
+    (period_end - period_start).clamp(0, FULL_WEIGHT_PERIOD) as u64
+}
+
+// Reads a provider's config from its PDA, or None when the provider never
+// registered. The seeds are checked by the caller's context, so a
+// registered provider's config can't be left out.
+fn load_provider_config(info: &AccountInfo) -> Result<Option<ProviderConfig>> {
+    if info.data_is_empty() {
+        return Ok(None);
+    }
+    require!(info.owner == &crate::ID, SubscriptionError::InvalidProviderConfig);
+    Ok(Some(ProviderConfig::try_deserialize(&mut &info.try_borrow_data()?[..])?))
+}
+
+// Writes back a config changed after load_provider_config
+fn store_provider_config(info: &AccountInfo, provider_config: &Option<ProviderConfig>) -> Result<()> {
+    if let Some(provider_config) = provider_config {
+        provider_config.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+    }
+    Ok(())
+}
+
+// Reads a provider's subscriber registry in either layout
+fn load_subscribers_list(info: &AccountInfo) -> Result<SubscribersList> {
+    if info.data_len() == SubscribersListV1::SIZE {
+        return Ok(SubscribersListV1::load(info)?.into());
+    }
+    SubscribersList::try_deserialize(&mut &info.try_borrow_data()?[..])
+}
+
+// Appends `entry` to the provider's subscriber registry, creating the
+// registry on first use and converting an original-layout one in place
+fn add_subscriber_entry<'info>(
+    subscribers_list: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    data_provider: &Pubkey,
+    program_id: &Pubkey,
+    entry: SubscriberEntry,
+) -> Result<()> {
+    let bump = find_pda_bump(subscribers_list, &[b"subscribers", data_provider.as_ref()], program_id)?;
+    if subscribers_list.data_is_empty() {
+        return create_program_account(
+            payer,
+            subscribers_list,
+            system_program,
+            &[b"subscribers", data_provider.as_ref(), &[bump]],
+            8 + SubscribersList::SIZE,
+            &SubscribersList { subscribers: vec![entry] },
+            program_id,
+        );
+    }
+
+    require!(subscribers_list.owner == program_id, SubscriptionError::InvalidSubscribersList);
+    let mut list = load_subscribers_list(subscribers_list)?;
+    if subscribers_list.data_len() == SubscribersListV1::SIZE {
+        resize_account(subscribers_list, payer, system_program, 8 + SubscribersList::SIZE)?;
+    }
+    list.subscribers.push(entry);
+    list.try_serialize(&mut &mut subscribers_list.try_borrow_mut_data()?[..])?;
+    Ok(())
+}
+
+// Grows or shrinks a subscription account to fit its recipient list. `payer`
+// covers extra rent and gets back what a shorter list frees up
+fn fit_subscription_account<'info>(
+    subscription: &Account<'info, Subscription>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let space = Subscription::space(&subscription.recipients);
+    let subscription_info = subscription.to_account_info();
+    if subscription_info.data_len() != space {
+        resize_account(&subscription_info, payer, system_program, space)?;
+    }
+    Ok(())
+}
+
+// Sets up the provider's QualityInfo on first subscription and converts an
+// original-layout account in place, so subscribing doesn't wait on
+// migrate_quality_info
+fn prepare_quality_info<'info>(
+    quality_info: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    data_provider: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<QualityInfo> {
+    let bump = find_pda_bump(quality_info, &[b"quality", data_provider.as_ref()], program_id)?;
+    if quality_info.data_is_empty() {
+        let mut created = QualityInfo::default();
+        created.initialize();
+        create_program_account(
+            payer,
+            quality_info,
+            system_program,
+            &[b"quality", data_provider.as_ref(), &[bump]],
+            // QualityInfo::SIZE already includes the discriminator
+            QualityInfo::SIZE,
+            &created,
+            program_id,
+        )?;
+        return Ok(created);
+    }
+
+    require!(quality_info.owner == program_id, SubscriptionError::InvalidQualityInfo);
+    if quality_info.data_len() == QualityInfoV1::SIZE {
+        let migrated = QualityInfo::from(QualityInfoV1::load(quality_info)?);
+        resize_account(quality_info, payer, system_program, QualityInfo::SIZE)?;
+        migrated.try_serialize(&mut &mut quality_info.try_borrow_mut_data()?[..])?;
+
+        emit!(QualityInfoMigratedEvent {
+            data_provider: *data_provider,
+            version: QUALITY_INFO_VERSION,
+        });
+        return Ok(migrated);
+    }
+
+    let loaded = QualityInfo::try_deserialize(&mut &quality_info.try_borrow_data()?[..])?;
+    require!(
+        loaded.version == QUALITY_INFO_VERSION,
+        SubscriptionError::QualityInfoNotMigrated
+    );
+    Ok(loaded)
+}
+
+// Reads a provider's QualityInfo PDA in either layout. The account may not
+// have been created yet.
+fn load_quality_info(info: &AccountInfo) -> Result<Option<QualityInfo>> {
+    if info.owner != &crate::ID || info.data_is_empty() {
+        return Ok(None);
+    }
+    if info.data_len() == QualityInfoV1::SIZE {
+        return Ok(Some(QualityInfoV1::load(info)?.into()));
+    }
+    Ok(Some(QualityInfo::try_deserialize(&mut &info.try_borrow_data()?[..])?))
+}
+
+// Conversion rate for paying in `mint`, either from its `AcceptedMint` entry or
+// 1:1 for the default payment token in `State`
+fn payment_conversion_rate(
+    state: &State,
+    mint: &Pubkey,
+    accepted_mint: Option<&Account<AcceptedMint>>,
+) -> Result<u64> {
+    match accepted_mint {
+        Some(accepted_mint) => {
+            require!(accepted_mint.mint == *mint, SubscriptionError::UnsupportedPaymentMint);
+            Ok(accepted_mint.conversion_rate)
+        }
+        None => {
+            require!(*mint == state.payment_spl_token, SubscriptionError::UnsupportedPaymentMint);
+            Ok(CONVERSION_RATE_SCALE)
+        }
+    }
+}
+
+// Converts an amount priced in the base fee unit into payment mint units
+fn convert_amount(amount: u64, conversion_rate: u64) -> u64 {
+    (amount as u128 * conversion_rate as u128 / CONVERSION_RATE_SCALE as u128) as u64
+}
+
+// Amount to send so that `net_amount` arrives after the mint's Token-2022
+// transfer fee, if it has one
+fn gross_up_for_transfer_fee(mint: &InterfaceAccount<Mint>, net_amount: u64) -> Result<u64> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(net_amount);
+    }
+
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    let fee = match mint_state.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => fee_config
+            .calculate_inverse_epoch_fee(Clock::get()?.epoch, net_amount)
+            .ok_or(SubscriptionError::TransferFeeOverflow)?,
+        Err(_) => 0,
+    };
+
+    net_amount
+        .checked_add(fee)
+        .ok_or(SubscriptionError::TransferFeeOverflow.into())
+}
+
+// Saves the evidence attached to rating `rating_id` into its per-rating PDA.
+// The evidence account must be passed exactly when evidence is provided.
+fn store_rating_evidence(
+    evidence_account: Option<&mut Account<RatingEvidence>>,
+    evidence: &Option<RatingEvidenceArgs>,
+    data_provider: Pubkey,
+    rater: Pubkey,
+    rating_id: u64,
+) -> Result<()> {
+    match (evidence_account, evidence) {
+        (Some(evidence_account), Some(evidence)) => {
+            require!(
+                evidence.uri.len() <= MAX_EVIDENCE_URI_LEN,
+                SubscriptionError::EvidenceUriTooLong
+            );
+
+            evidence_account.data_provider = data_provider;
+            evidence_account.rater = rater;
+            evidence_account.rating_id = rating_id;
+            evidence_account.content_hash = evidence.content_hash;
+            evidence_account.uri = evidence.uri.clone();
+            Ok(())
+        }
+        (None, None) => Ok(()),
+        _ => err!(SubscriptionError::EvidenceAccountMismatch),
+    }
+}
+
+// Whether `account` is `wallet`'s associated token account for `mint` under
+// `token_program`
+fn is_associated_token_account(
+    account: &InterfaceAccount<token_interface::TokenAccount>,
+    wallet: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> bool {
+    account.key() == get_associated_token_address_with_program_id(wallet, mint, token_program)
+        && account.mint == *mint
+        && account.owner == *wallet
+}
+
+// Moves payment tokens with `transfer_checked`, which works for both the
+// legacy token program and Token-2022
+fn transfer_payment<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &InterfaceAccount<'info, token_interface::TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    to: &InterfaceAccount<'info, token_interface::TokenAccount>,
+    authority: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority,
+    };
+
+    token_interface::transfer_checked(
+        CpiContext::new(token_program.to_account_info(), cpi_accounts),
+        amount,
+        mint.decimals,
+    )
+}
+
+#[account]
+pub struct Subscription {
+    pub version: u8,          // SUBSCRIPTION_VERSION
+    pub start_time: i64,      // Start of the current paid period
+    pub end_time: i64,
+    pub recipients: Vec<Recipient>,
+    pub payment_mint: Pubkey, // Mint the subscription was paid in; renewals and refunds use it too.
+                              // Pubkey::default() for migrated subscriptions, which renew in lamports
+    pub rated_end_time: i64,  // End time of the last period that was rated
+    pub last_recipient_update: i64, // Time of the last recipient change
+    pub subscriber: Pubkey,   // Beneficiary; the only one who can manage or rate the subscription
+    pub payer: Pubkey,        // Who paid; differs from subscriber for gifts
+    pub refund_to_payer: bool, // Refunds go to the payer instead of the subscriber
+    pub pass_mint: Option<Pubkey>, // When set, whoever holds this token holds the subscription
+    pub paid_amount: u64,     // Provider fee paid, in the payment mint, for paid_since..end_time
+    pub paid_since: i64,
+    pub counted: bool,        // Holds one of the provider's active_subscriptions until ended or released
+}
+
+impl Subscription {
+    // Everything but the recipients themselves: u8 + 2 i64s (8 bytes each) + Vec length + Pubkey (32 bytes) + 2 i64s (8 bytes each) + 2 Pubkeys (32 bytes each) + bool + Option<Pubkey> + u64 + i64 + bool
+    pub const BASE_SIZE: usize = 1 + 8 + 8 + 4 + 32 + 8 + 8 + 32 + 32 + 1 + 33 + 8 + 8 + 1;
+
+    // Account space, including the discriminator, for a subscription
+    // delivering to `recipients`
+    pub fn space(recipients: &[Recipient]) -> usize {
+        8 + Self::BASE_SIZE + recipients.iter().map(Recipient::serialized_len).sum::<usize>()
+    }
+
+    // Records a provider payment; call before moving end_time. Time still
+    // unused from an active period stays refundable
+    pub fn record_payment(&mut self, amount: u64, current_time: i64) {
+        if self.end_time > current_time {
+            self.paid_amount += amount;
+        } else {
+            self.paid_amount = amount;
+            self.paid_since = current_time;
+        }
+    }
+
+    // Share of the paid amount covering the time left after `current_time`
+    pub fn unused_amount(&self, current_time: i64) -> u64 {
+        if self.end_time <= current_time || self.end_time <= self.paid_since {
+            return 0;
+        }
+        let remaining = (self.end_time - current_time.max(self.paid_since)) as u128;
+        let paid_period = (self.end_time - self.paid_since) as u128;
+        (self.paid_amount as u128 * remaining / paid_period) as u64
+    }
+
+    // Whether `holder` may manage, rate and receive this subscription
+    pub fn is_held_by(&self, holder: &Pubkey, pass_token_account: Option<&TokenAccount>) -> bool {
+        match self.pass_mint {
+            None => *holder == self.subscriber,
+            Some(pass_mint) => pass_token_account.is_some_and(|account| {
+                account.mint == pass_mint && account.owner == *holder && account.amount == 1
+            }),
+        }
+    }
+
+    // Account that receives refunds and compensation
+    pub fn refund_recipient(&self) -> Pubkey {
+        if self.refund_to_payer {
+            self.payer
+        } else {
+            self.subscriber
+        }
+    }
+
+    // Like refund_recipient, but a subscription represented by a pass refunds
+    // whoever holds the pass in `pass_token_account`. None when the pass
+    // holder is needed and the token account doesn't hold the pass.
+    pub fn refund_recipient_for(&self, pass_token_account: Option<&TokenAccount>) -> Option<Pubkey> {
+        match self.pass_mint {
+            Some(pass_mint) if !self.refund_to_payer => pass_token_account
+                .filter(|account| account.mint == pass_mint && account.amount == 1)
+                .map(|account| account.owner),
+            _ => Some(self.refund_recipient()),
+        }
+    }
+
+    // Recipient changes must be live and rate limited
+    pub fn check_recipient_update(&self, current_time: i64) -> Result<()> {
+        require!(self.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        require!(
+            current_time < self.end_time,
+            SubscriptionError::SubscriptionAlreadyEnded
+        );
+        require!(
+            current_time >= self.last_recipient_update + RECIPIENT_UPDATE_COOLDOWN,
+            SubscriptionError::RecipientUpdateTooSoon
+        );
+        Ok(())
+    }
+
+    // Takes one of the provider's active subscription slots. Subscriptions
+    // to unregistered providers aren't counted
+    pub fn count_against(&mut self, provider_config: Option<&mut ProviderConfig>) {
+        if let Some(provider_config) = provider_config {
+            if !self.counted {
+                provider_config.active_subscriptions += 1;
+                self.counted = true;
+            }
+        }
+    }
+
+    // Gives the subscription's slot back to the provider, once
+    pub fn release(&mut self, provider_config: Option<&mut ProviderConfig>) -> Result<()> {
+        if self.counted {
+            let provider_config = provider_config.ok_or(SubscriptionError::ProviderConfigMissing)?;
+            provider_config.active_subscriptions = provider_config.active_subscriptions.saturating_sub(1);
+            self.counted = false;
+        }
+        Ok(())
+    }
+
+    // Whether renewals and refunds are paid in lamports
+    pub fn pays_in_lamports(&self) -> bool {
+        self.payment_mint == Pubkey::default()
+    }
+
+    // Start of the period a renewal to `new_end_time` pays for: the current
+    // end time, or now if the subscription has lapsed
+    pub fn renewal_start(&self, new_end_time: i64, current_time: i64) -> Result<i64> {
+        // Verify subscription exists (non-zero end time indicates existence)
+        require!(self.end_time > 0, SubscriptionError::SubscriptionNotFound);
+
+        let renewal_time = self.end_time.max(current_time);
+        require!(
+            new_end_time >= renewal_time + MIN_SUBSCRIPTION_PERIOD,
+            SubscriptionError::PeriodTooShort
+        );
+        Ok(renewal_time)
+    }
+
+    // Records the holder's rating of the current period, unless it was
+    // already rated
+    pub fn rate_period(
+        &mut self,
+        quality_info: &mut QualityInfo,
+        rater: Pubkey,
+        ratings: QualityRatings,
+    ) -> Result<()> {
+        if self.can_rate() {
+            quality_info.record_rating(rater, ratings, self)?;
+            self.rated_end_time = self.end_time;
+        }
+        Ok(())
+    }
+
+    // Starts the renewed period paid for with `amount`
+    pub fn extend(
+        &mut self,
+        amount: u64,
+        renewal_time: i64,
+        new_end_time: i64,
+        recipients: Vec<Recipient>,
+        current_time: i64,
+    ) {
+        self.record_payment(amount, current_time);
+        self.start_time = renewal_time;
+        self.end_time = new_end_time;
+        self.recipients = recipients;
+    }
+
+    // Whether the current subscription period is still unrated
+    pub fn can_rate(&self) -> bool {
+        self.rated_end_time != self.end_time
+    }
+
+    // Rating weight for the current period: the number of seconds paid for,
+    // capped at FULL_WEIGHT_PERIOD
+    pub fn period_weight(&self) -> u64 {
+        rating_weight(self.start_time, self.end_time)
+    }
+}
+
+// Subscription layout before recipients were validated and payments were
+// tracked. Only used to read and migrate old accounts.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SubscriptionV1 {
+    pub end_time: i64,
+    pub recipient: String,
+}
+
+impl SubscriptionV1 {
+    pub const SIZE: usize = 8 + 8 + 32; // discriminator + i64 + String as allocated
+
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() >= 8 && data[..8] == Subscription::DISCRIMINATOR,
+            SubscriptionError::SubscriptionNotFound
+        );
+        Ok(Self::deserialize(&mut &data[8..])?)
+    }
+
+    // The old layout didn't record when the period started or what it cost,
+    // so the current period counts from the migration and isn't refundable
+    pub fn migrate(self, subscriber: Pubkey, current_time: i64) -> Subscription {
+        Subscription {
+            version: SUBSCRIPTION_VERSION,
+            start_time: current_time.min(self.end_time),
+            end_time: self.end_time,
+            recipients: vec![Recipient {
+                label: None,
+                target: DeliveryTarget::from_legacy(self.recipient, subscriber),
+            }],
+            payment_mint: Pubkey::default(),
+            rated_end_time: 0,
+            last_recipient_update: 0,
+            subscriber,
+            payer: subscriber,
+            refund_to_payer: false,
+            pass_mint: None,
+            paid_amount: 0,
+            paid_since: current_time,
+            counted: false,
+        }
+    }
+}
+
+#[account]
+pub struct AcceptedMint {
+    pub mint: Pubkey,
+    pub conversion_rate: u64, // Mint units per base fee unit, scaled by CONVERSION_RATE_SCALE
+}
+
+impl AcceptedMint {
+    pub const SIZE: usize = 32 + 8; // Pubkey (32 bytes) + u64 (8 bytes)
+}
+
+#[account]
+pub struct SubscribersList {
+    pub subscribers: Vec<SubscriberEntry>,
+}
+
+impl SubscribersList {
+    pub const SIZE: usize = 4 + (SubscriberEntry::SIZE * 10); // Vec length (4 bytes) + space for 10 entries
+}
+
+// Original SubscribersList layout, listing bare subscriber keys. Only used to
+// read and migrate old accounts.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SubscribersListV1 {
+    pub subscribers: Vec<Pubkey>,
+}
+
+impl SubscribersListV1 {
+    pub const SIZE: usize = 8 + 4 + (32 * 10);
+
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() >= 8 && data[..8] == SubscribersList::DISCRIMINATOR,
+            SubscriptionError::InvalidSubscribersList
+        );
+        Ok(Self::deserialize(&mut &data[8..])?)
+    }
+}
+
+impl From<SubscribersListV1> for SubscribersList {
+    // Every original entry was an individual subscription
+    fn from(v1: SubscribersListV1) -> Self {
+        SubscribersList {
+            subscribers: v1
+                .subscribers
+                .into_iter()
+                .map(|subscriber| SubscriberEntry { subscriber, seats: 1 })
+                .collect(),
+        }
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct QualityInfo {
+    pub version: u8,                 // 1 byte, QUALITY_INFO_VERSION
+    pub current_index: u8,           // 1 byte
+    pub qualities: Vec<QualityRecord>, // Using our new struct instead of tuple
+    pub rating_count: u64,           // 8 bytes, every rating ever recorded
+    pub struck_count: u64,           // 8 bytes, ratings struck through disputes
+    pub total_weight: u64,           // 8 bytes, sum of rating weights
+    pub accuracy: QualityStats,      // Per-dimension aggregates
+    pub latency: QualityStats,
+    pub uptime: QualityStats,
+    pub completeness: QualityStats,
+}
+
+impl QualityInfo {
+    pub const MAX_QUALITY_RECORDS: usize = 10; // Define max records for space calculation
+
+    pub const SIZE: usize = 8 +  // discriminator
+                           1 +   // version
+                           1 +   // current_index
+                           4 +   // vec length
+                           (Self::MAX_QUALITY_RECORDS * QualityRecord::SIZE) + // qualities array size
+                           8 +   // rating_count
+                           8 +   // struck_count
+                           8 +   // total_weight
+                           4 * QualityStats::SIZE; // per-dimension aggregates
+
+    // Sets up a freshly created account
+    pub fn initialize(&mut self) {
+        self.version = QUALITY_INFO_VERSION;
+        self.current_index = 0;
+        self.rating_count = 0;
+        self.struck_count = 0;
+        self.total_weight = 0;
+        self.accuracy = QualityStats::default();
+        self.latency = QualityStats::default();
+        self.uptime = QualityStats::default();
+        self.completeness = QualityStats::default();
+
+        // Initialize the qualities vector with default values
+        self.qualities = vec![
+            QualityRecord::default();
+            MAX_QUALITY_RECORDS      // Create MAX_QUALITY_RECORDS number of entries
+        ];
+    }
+
+    // Stores a rating for the subscription's current period in the ring buffer
+    // and folds it into the running aggregates. Ratings are weighted by the
+    // length of the rated period, so short subscriptions move the weighted
+    // aggregates and the average less. The weight is capped at
+    // FULL_WEIGHT_PERIOD so one long, cheap subscription can't dominate.
+    pub fn record_rating(
+        &mut self,
+        rater: Pubkey,
+        ratings: QualityRatings,
+        subscription: &Subscription,
+    ) -> Result<()> {
+        self.record_period_rating(rater, ratings, subscription.start_time, subscription.end_time)
+    }
+
+    // Same as record_rating, for a period that isn't an individual
+    // subscription's, such as a group's
+    pub fn record_period_rating(
+        &mut self,
+        rater: Pubkey,
+        ratings: QualityRatings,
+        period_start: i64,
+        period_end: i64,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let weight = rating_weight(period_start, period_end);
+        let current_idx = self.current_index as usize;
+        self.qualities[current_idx] = QualityRecord {
+            rater,
+            ratings,
+            weight,
+            timestamp: clock.unix_timestamp,
+            slot: clock.slot,
+            period_start,
+            period_end,
+            score_bps: None,
+        };
+        self.current_index = (current_idx as u8 + 1) % MAX_QUALITY_RECORDS as u8;
+
+        self.fold_rating(ratings, weight);
+        self.qualities[current_idx].score_bps = Some(self.score_bps());
+        Ok(())
+    }
+
+    // Folds a rating into the running aggregates
+    fn fold_rating(&mut self, ratings: QualityRatings, weight: u64) {
+        let alpha_bps = if self.rating_count == 0 {
+            BPS_DENOMINATOR // First rating seeds the moving average
+        } else {
+            EMA_ALPHA_BPS * weight.min(FULL_WEIGHT_PERIOD as u64) / FULL_WEIGHT_PERIOD as u64
+        };
+        self.accuracy.record(ratings.accuracy, weight, alpha_bps);
+        self.latency.record(ratings.latency, weight, alpha_bps);
+        self.uptime.record(ratings.uptime, weight, alpha_bps);
+        self.completeness.record(ratings.completeness, weight, alpha_bps);
+        self.rating_count += 1;
+        self.total_weight = self.total_weight.saturating_add(weight);
+    }
+
+    // Whether the score stayed below `threshold_bps` after each of the last
+    // `window` ratings. Struck and migrated ratings left no score behind, so
+    // they break the run.
+    pub fn stayed_below(&self, threshold_bps: u64, window: u64) -> bool {
+        let window = window as usize;
+        if window == 0 || window > MAX_QUALITY_RECORDS || self.rating_count < window as u64 {
+            return false;
+        }
+        (1..=window).all(|back| {
+            let idx = (self.current_index as usize + MAX_QUALITY_RECORDS - back) % MAX_QUALITY_RECORDS;
+            self.qualities[idx]
+                .score_bps
+                .is_some_and(|score| score < threshold_bps)
+        })
+    }
+
+    // Overall reputation score: the weighted mean rating across dimensions, in
+    // basis points of the 0-100 scale. Built from the sums so struck ratings
+    // drop out of it; providers without standing ratings score as neutral.
+    pub fn score_bps(&self) -> u64 {
+        let dimensions = [self.accuracy, self.latency, self.uptime, self.completeness];
+        let (total, count) = if self.total_weight > 0 {
+            (
+                dimensions.iter().map(|stats| stats.weighted_sum as u128).sum::<u128>(),
+                self.total_weight as u128,
+            )
+        } else {
+            (
+                dimensions.iter().map(|stats| stats.sum as u128).sum::<u128>(),
+                (self.rating_count - self.struck_count) as u128,
+            )
+        };
+        if count == 0 {
+            return NEUTRAL_REPUTATION_SCORE_BPS;
+        }
+        (total * 100 / (count * dimensions.len() as u128)) as u64
+    }
+
+    // Removes a disputed rating from the sums behind the score and from the
+    // ring buffer. The moving averages can't be unwound, so they keep the
+    // rating's contribution and are only reported.
+    pub fn strike_rating(&mut self, rating_id: u64, record: &QualityRecord) {
+        let ratings = record.ratings;
+        self.accuracy.remove(ratings.accuracy, record.weight);
+        self.latency.remove(ratings.latency, record.weight);
+        self.uptime.remove(ratings.uptime, record.weight);
+        self.completeness.remove(ratings.completeness, record.weight);
+        self.struck_count += 1;
+        self.total_weight = self.total_weight.saturating_sub(record.weight);
+
+        // Clear the ring buffer slot unless it has been overwritten since
+        if self.rating_count - rating_id <= MAX_QUALITY_RECORDS as u64 {
+            let idx = (rating_id % MAX_QUALITY_RECORDS as u64) as usize;
+            self.qualities[idx] = QualityRecord::default();
+        }
+    }
+}
+
+// Original QualityInfo layout: a ring buffer of single 0-100 ratings.
+// Only used to read and migrate old accounts.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct QualityInfoV1 {
+    pub subscriber: Pubkey,          // Never written
+    pub quality: u8,                 // Never written
+    pub current_index: u8,
+    pub qualities: Vec<QualityRecordV1>,
+}
+
+impl QualityInfoV1 {
+    pub const SIZE: usize = 8 + 32 + 1 + 1 + 4 + (MAX_QUALITY_RECORDS * QualityRecordV1::SIZE);
+
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() >= 8 && data[..8] == QualityInfo::DISCRIMINATOR,
+            SubscriptionError::InvalidQualityInfo
+        );
+        Ok(Self::deserialize(&mut &data[8..])?)
+    }
+}
+
+impl From<QualityInfoV1> for QualityInfo {
+    // Replays the stored ratings oldest first, so rating ids line up with the
+    // ring buffer again. A v1 rating applies to every dimension and counts as
+    // a minimum-length period; time, slot and period weren't recorded.
+    fn from(v1: QualityInfoV1) -> Self {
+        let mut quality_info = QualityInfo::default();
+        quality_info.initialize();
+
+        let len = v1.qualities.len();
+        let start = if len == 0 { 0 } else { v1.current_index as usize % len };
+        let records = v1.qualities[start..]
+            .iter()
+            .chain(v1.qualities[..start].iter())
+            .filter(|record| record.provider != Pubkey::default());
+        for record in records {
+            let ratings = QualityRatings {
+                accuracy: record.quality,
+                latency: record.quality,
+                uptime: record.quality,
+                completeness: record.quality,
+            };
+            let weight = MIN_SUBSCRIPTION_PERIOD as u64;
+            let idx = (quality_info.rating_count % MAX_QUALITY_RECORDS as u64) as usize;
+            quality_info.qualities[idx] = QualityRecord {
+                rater: record.provider,
+                ratings,
+                weight,
+                ..QualityRecord::default()
+            };
+            quality_info.fold_rating(ratings, weight);
+        }
+        quality_info.current_index = (quality_info.rating_count % MAX_QUALITY_RECORDS as u64) as u8;
+        quality_info
+    }
+}
+
+#[account]
+pub struct RatingEvidence {
+    pub data_provider: Pubkey,
+    pub rater: Pubkey,
+    pub rating_id: u64,          // Sequence number of the rating (QualityInfo.rating_count when rated)
+    pub content_hash: [u8; 32],  // Hash of the off-chain evidence
+    pub uri: String,             // Where the evidence can be fetched
+}
+
+impl RatingEvidence {
+    pub const SIZE: usize = 32 + 32 + 8 + 32 + 4 + MAX_EVIDENCE_URI_LEN;
+}
+
+#[account]
+pub struct ProviderConfig {
+    pub data_provider: Pubkey,
+    pub active_subscriptions: u32,     // Subscriptions holding a slot; see Subscription.counted
+    pub stake: u64,                    // Lamports held in the stake vault
+    pub withdrawal_requested_at: i64,  // 0 when no withdrawal is pending
+    pub ratings_at_last_slash: u64,    // QualityInfo.rating_count when last slashed
+    pub fee_per_day: u64,              // Provider's price; 0 uses State.fee_per_day
+    pub encryption_key: [u8; 32],      // x25519 public key; all zeros when unpublished
+    pub max_recipients: u8,            // Recipients per subscription; 0 allows one
+    pub max_subscribers: u32,          // Cap on active_subscriptions; 0 is unlimited
+}
+
+// Settings of a provider that never registered: no stake or capacity cap,
+// the default price and a single recipient per subscription
+static UNREGISTERED_PROVIDER: ProviderConfig = ProviderConfig {
+    data_provider: Pubkey::new_from_array([0; 32]),
+    active_subscriptions: 0,
+    stake: 0,
+    withdrawal_requested_at: 0,
+    ratings_at_last_slash: 0,
+    fee_per_day: 0,
+    encryption_key: [0; 32],
+    max_recipients: 0,
+    max_subscribers: 0,
+};
+
+impl ProviderConfig {
+    pub const SIZE: usize = 32 + 4 + 8 + 8 + 8 + 8 + 32 + 1 + 4;
+
+    // Registration is optional for subscribers; unregistered providers use
+    // the defaults
+    pub fn settings(provider_config: Option<&ProviderConfig>) -> &ProviderConfig {
+        provider_config.unwrap_or(&UNREGISTERED_PROVIDER)
+    }
+
+    // Slots left under max_subscribers, or None when uncapped
+    pub fn free_slots(&self) -> Option<u32> {
+        if self.max_subscribers == 0 {
+            None
+        } else {
+            Some(self.max_subscribers.saturating_sub(self.active_subscriptions))
+        }
+    }
+
+    pub fn recipient_limit(&self) -> usize {
+        (self.max_recipients as usize).clamp(1, MAX_RECIPIENTS)
+    }
+
+    pub fn check_recipients(&self, recipients: &[Recipient]) -> Result<()> {
+        require!(
+            !recipients.is_empty() && recipients.len() <= self.recipient_limit(),
+            SubscriptionError::InvalidRecipientCount
+        );
+        for recipient in recipients {
+            recipient.validate()?;
+            self.check_delivery_target(&recipient.target)?;
+        }
+        Ok(())
+    }
+
+    pub fn has_encryption_key(&self) -> bool {
+        self.encryption_key != [0u8; 32]
+    }
+
+    // Encrypted targets can only be decrypted once the provider has published a key
+    pub fn check_delivery_target(&self, target: &DeliveryTarget) -> Result<()> {
+        if matches!(target, DeliveryTarget::Encrypted(_)) {
+            require!(
+                self.has_encryption_key(),
+                SubscriptionError::ProviderEncryptionKeyMissing
+            );
+        }
+        Ok(())
+    }
+
+    pub fn effective_fee_per_day(&self, state: &State) -> u64 {
+        if self.fee_per_day > 0 {
+            self.fee_per_day
+        } else {
+            state.fee_per_day
+        }
+    }
+}
+
+// Holds a provider's staked lamports
+#[account]
+pub struct StakeVault {}
+
+#[account]
+pub struct Dispute {
+    pub data_provider: Pubkey,
+    pub rating_id: u64,
+    pub record: QualityRecord,   // Copy of the disputed rating
+    pub response_hash: [u8; 32], // Hash of the provider's off-chain response
+    pub bond: u64,               // Lamports posted by the provider
+    pub opened_at: i64,
+}
+
+impl Dispute {
+    pub const SIZE: usize = 32 + 8 + QualityRecord::SIZE + 32 + 8 + 8;
+}
+
+// Wallets a provider refuses to serve
+#[account]
+pub struct Blocklist {
+    pub data_provider: Pubkey,
+    pub banned: Vec<Pubkey>,
+}
+
+impl Blocklist {
+    pub const SIZE: usize = 32 + 4 + (32 * MAX_BLOCKLIST_LEN);
+}
+
+// Subscribers queued for a slot at a provider that is at capacity. The
+// account holds each entry's deposit on top of its rent.
+#[account]
+pub struct Waitlist {
+    pub data_provider: Pubkey,
+    pub queue: Vec<WaitlistEntry>,  // Front of the queue claims freed slots first
+}
+
+impl Waitlist {
+    pub const SIZE: usize = 32 + 4 + (WaitlistEntry::SIZE * MAX_WAITLIST_LEN);
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct WaitlistEntry {
+    pub subscriber: Pubkey,
+    pub joined_at: i64,
+    pub deposit: u64,  // Lamports refunded on leaving or claiming a slot
+}
+
+impl WaitlistEntry {
+    pub const SIZE: usize = 32 + 8 + 8;
+
+    // Expired entries no longer hold a place in the queue
+    pub fn is_live(&self, current_time: i64) -> bool {
+        current_time < self.joined_at + WAITLIST_ENTRY_TTL
+    }
+}
+
+// A subscription shared by up to `seats` member wallets
+#[account]
+pub struct GroupSubscription {
+    pub admin: Pubkey,         // Pays for the group and manages its members
+    pub data_provider: Pubkey,
+    pub group_id: u64,         // Chosen by the admin; lets one admin run several groups per provider
+    pub seats: u32,            // Seats purchased up front
+    pub members: u32,          // Seats currently assigned
+    pub start_time: i64,       // Start of the current paid period
+    pub end_time: i64,
+    pub payment_mint: Pubkey,  // Renewals are paid in the same mint
+    pub counted: bool,         // Holds one of the provider's active_subscriptions until ended
+    pub period_ratings: u32,   // Member ratings of the current period, at most one per seat
+}
+
+impl GroupSubscription {
+    pub const SIZE: usize = 32 + 32 + 8 + 4 + 4 + 8 + 8 + 32 + 1 + 4;
+
+    // Members rate the current period up to RATING_WINDOW after it ends.
+    // Each member rates a period at most once, and a period gets at most one
+    // rating per seat, so reshuffling members can't add ratings. Members
+    // added after the period ended didn't receive it and can't rate it.
+    pub fn check_member_rating(&self, membership: &GroupMembership, current_time: i64) -> Result<()> {
+        require!(self.end_time > 0, SubscriptionError::SubscriptionNotFound);
+        require!(
+            current_time < self.end_time + RATING_WINDOW,
+            SubscriptionError::RatingWindowClosed
+        );
+        require!(
+            membership.added_at < self.end_time,
+            SubscriptionError::MemberJoinedAfterPeriod
+        );
+        require!(
+            membership.rated_end_time != self.end_time && self.period_ratings < self.seats,
+            SubscriptionError::AlreadyRated
+        );
+        Ok(())
+    }
+}
+
+// Grants `member` a seat in `group`; closed when the member is removed
+#[account]
+pub struct GroupMembership {
+    pub group: Pubkey,
+    pub member: Pubkey,
+    pub added_at: i64,
+    pub recipient: Recipient,  // Where the provider delivers the member's data
+    pub rated_end_time: i64,   // End time of the last group period the member rated
+}
+
+impl GroupMembership {
+    pub const SIZE: usize = 32 + 32 + 8 + Recipient::SIZE + 8;
+}
+
+#[account]
+pub struct Coupon {
+    pub data_provider: Pubkey,
+    pub coupon_id: u64,
+    pub discount: CouponDiscount,
+    pub expires_at: i64,
+    pub max_redemptions: u32,
+    pub redemptions: u32,
+    pub whitelist: Vec<Pubkey>, // Empty whitelist means anyone can redeem
+}
+
+impl Coupon {
+    pub const SIZE: usize = 32 + // data provider pubkey
+                           8 +   // coupon id
+                           9 +   // discount (enum tag + u64)
+                           8 +   // expires_at
+                           4 +   // max_redemptions
+                           4 +   // redemptions
+                           4 +   // vec length
+                           (32 * MAX_COUPON_WHITELIST); // whitelisted subscribers
+
+    // Validates the coupon for a subscriber, counts the redemption and
+    // returns the discounted fee
+    pub fn redeem(&mut self, subscriber: &Pubkey, current_time: i64, fee: u64) -> Result<u64> {
+        require!(current_time < self.expires_at, SubscriptionError::CouponExpired);
+        require!(
+            self.redemptions < self.max_redemptions,
+            SubscriptionError::CouponExhausted
+        );
+        require!(
+            self.whitelist.is_empty() || self.whitelist.contains(subscriber),
+            SubscriptionError::CouponNotWhitelisted
+        );
+
+        self.redemptions += 1;
+
+        Ok(match self.discount {
+            CouponDiscount::Percent(percent) => fee - (fee * percent as u64) / 100,
+            CouponDiscount::Fixed(amount) => fee.saturating_sub(amount),
+        })
+    }
+}
+
+// Context structs for instructions
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + State::SIZE,
+        seeds = [b"storage"],
+        bump
+    )]
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK: This is the NFT program ID
+    pub nft_program: UncheckedAccount<'info>,
+    /// CHECK: SPL token account for payment
+    pub payment_spl_token: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(recipients: Vec<Recipient>)]
+pub struct Subscribe<'info> {
+    #[account(mut)]
+    pub state: Account<'info, State>,
+    // Pays for the subscription
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    // Owner of the subscription; the subscriber itself unless gifted. Signs so
+    // nobody can take a wallet's subscription slot or pick its recipients.
+    // Gets its waitlist deposit back when taking a reserved slot
+    #[account(mut)]
+    pub beneficiary: Signer<'info>,
+    /// CHECK: Data provider account
+    #[account(mut)]
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    /// CHECK: Provider's QualityInfo in either layout; created or migrated by the handler
+    #[account(mut, seeds = [b"quality", data_provider.key().as_ref()], bump)]
+    pub quality_info: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = subscriber,
+        space = Subscription::space(&recipients),
+        seeds = [b"subscription", beneficiary.key().as_ref(), data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscription: Account<'info, Subscription>,
+    /// CHECK: Provider's subscriber registry in either layout; created or migrated by the handler
+    #[account(mut, seeds = [b"subscribers", data_provider.key().as_ref()], bump)]
+    pub subscribers_list: UncheckedAccount<'info>,
+    /// CHECK: Owner account from state
+    #[account(mut, constraint = owner.key() == state.owner)]
+    pub owner: UncheckedAccount<'info>,
+    #[account(mint::token_program = token_program)]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"accepted_mint", payment_mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Option<Account<'info, AcceptedMint>>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &data_provider_payment_ata,
+            &data_provider.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidProviderPaymentAccount
+    )]
+    pub data_provider_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &subscriber_payment_ata,
+            &subscriber.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidSubscriberPaymentAccount
+    )]
+    pub subscriber_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &owner_payment_ata,
+            &state.owner,
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidOwnerPaymentAccount
+    )]
+    pub owner_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub system_program: Program<'info, System>,
+    // Payment token program, either the legacy token program or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+    // NFT check uses the legacy token program
+    pub nft_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = coupon.data_provider == data_provider.key() @ SubscriptionError::InvalidCoupon
+    )]
+    pub coupon: Option<Account<'info, Coupon>>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    /// CHECK: Provider's waitlist; read only once someone has queued
+    #[account(mut, seeds = [b"waitlist", data_provider.key().as_ref()], bump)]
+    pub waitlist: UncheckedAccount<'info>,
+    // Pass accounts; provide all of them to represent the subscription as a token
+    #[account(
+        init,
+        payer = subscriber,
+        seeds = [b"pass", subscription.key().as_ref()],
+        bump,
+        mint::decimals = 0,
+        mint::authority = pass_mint,
+        mint::token_program = pass_token_program,
+    )]
+    pub pass_mint: Option<Account<'info, token::Mint>>,
+    #[account(
+        init,
+        payer = subscriber,
+        associated_token::mint = pass_mint,
+        associated_token::authority = beneficiary,
+        associated_token::token_program = pass_token_program,
+    )]
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    pub pass_token_program: Option<Program<'info, Token>>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+}
+
+#[derive(Accounts)]
+pub struct RenewSubscription<'info> {
+    #[account(mut)]
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    #[account(mut)]
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    // Required when the subscription is represented by a pass
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), pass_token_account.as_deref())
+            @ SubscriptionError::NotSubscriptionHolder
+    )]
+    pub subscription: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    /// CHECK: Owner account from state
+    #[account(mut, constraint = owner.key() == state.owner)]
+    pub owner: UncheckedAccount<'info>,
+    #[account(
+        mint::token_program = token_program,
+        constraint = payment_mint.key() == subscription.payment_mint @ SubscriptionError::PaymentMintMismatch
+    )]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"accepted_mint", payment_mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Option<Account<'info, AcceptedMint>>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &data_provider_payment_ata,
+            &data_provider.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidProviderPaymentAccount
+    )]
+    pub data_provider_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &subscriber_payment_ata,
+            &subscriber.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidSubscriberPaymentAccount
+    )]
+    pub subscriber_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &owner_payment_ata,
+            &state.owner,
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidOwnerPaymentAccount
+    )]
+    pub owner_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub nft_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = coupon.data_provider == data_provider.key() @ SubscriptionError::InvalidCoupon
+    )]
+    pub coupon: Option<Account<'info, Coupon>>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    /// CHECK: Provider's waitlist; read only once someone has queued
+    #[account(mut, seeds = [b"waitlist", data_provider.key().as_ref()], bump)]
+    pub waitlist: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RenewLegacySubscription<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account, paid in lamports
+    #[account(mut)]
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), None)
+            @ SubscriptionError::NotSubscriptionHolder,
+        constraint = subscription.pays_in_lamports() @ SubscriptionError::PaymentMintMismatch
+    )]
+    pub subscription: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    /// CHECK: Owner account from state
+    #[account(mut, constraint = owner.key() == state.owner)]
+    pub owner: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    #[account(
+        mut,
+        constraint = coupon.data_provider == data_provider.key() @ SubscriptionError::InvalidCoupon
+    )]
+    pub coupon: Option<Account<'info, Coupon>>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    /// CHECK: Provider's waitlist; read only once someone has queued
+    #[account(mut, seeds = [b"waitlist", data_provider.key().as_ref()], bump)]
+    pub waitlist: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManageRecipients<'info> {
+    // Pays for, or gets back, the rent of a changed recipient list
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    // Required when the subscription is represented by a pass
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), pass_token_account.as_deref())
+            @ SubscriptionError::NotSubscriptionHolder
+    )]
+    pub subscription: Account<'info, Subscription>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelSubscription<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    // Required when the subscription is represented by a pass
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), pass_token_account.as_deref())
+            @ SubscriptionError::NotSubscriptionHolder
+    )]
+    pub subscription: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    #[account(
+        init,
+        payer = subscriber,
+        space = 8 + RatingEvidence::SIZE,
+        seeds = [b"evidence", data_provider.key().as_ref(), &quality_info.rating_count.to_le_bytes()],
+        bump
+    )]
+    pub evidence: Option<Account<'info, RatingEvidence>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub nft_token_account: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct EndSubscription<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    // Required when the subscription is represented by a pass
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), pass_token_account.as_deref())
+            @ SubscriptionError::NotSubscriptionHolder
+    )]
+    pub subscription: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    #[account(
+        init,
+        payer = subscriber,
+        space = 8 + RatingEvidence::SIZE,
+        seeds = [b"evidence", data_provider.key().as_ref(), &quality_info.rating_count.to_le_bytes()],
+        bump
+    )]
+    pub evidence: Option<Account<'info, RatingEvidence>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReleaseExpiredSubscription<'info> {
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscription: Account<'info, Subscription>,
+}
+
+#[derive(Accounts)]
+pub struct GetSubscribers<'info> {
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's subscriber registry in either layout
+    #[account(
+        owner = crate::ID,
+        seeds = [b"subscribers", data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscribers_list: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSubscription<'info> {
+    pub state: Account<'info, State>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetProviderReputation<'info> {
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: QualityInfo PDA in either layout, read by load_quality_info
+    #[account(
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump
+    )]
+    pub quality_info: UncheckedAccount<'info>,
+}
+
+// Context struct for admin functions
+#[derive(Accounts)]
+pub struct AdminFunction<'info> {
+    #[account(
+        mut,
+        constraint = state.owner == owner.key() @ SubscriptionError::NotOwner
+    )]
+    pub state: Account<'info, State>,
+
+    #[account(
+        signer,
+        constraint = owner.key() == state.owner @ SubscriptionError::NotOwner
+    )]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddAcceptedMint<'info> {
+    #[account(
+        constraint = state.owner == owner.key() @ SubscriptionError::NotOwner
+    )]
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + AcceptedMint::SIZE,
+        seeds = [b"accepted_mint", mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Account<'info, AcceptedMint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveAcceptedMint<'info> {
+    #[account(
+        constraint = state.owner == owner.key() @ SubscriptionError::NotOwner
+    )]
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"accepted_mint", accepted_mint.mint.as_ref()],
+        bump
+    )]
+    pub accepted_mint: Account<'info, AcceptedMint>,
+}
+
+#[derive(Accounts)]
+pub struct StoreDataQuality<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: AccountInfo<'info>,
+    #[account(mut, seeds = [b"quality", data_provider.key().as_ref()], bump)]
+    pub quality_info: Account<'info, QualityInfo>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Context struct for storing quality data
+#[derive(Accounts)]
+pub struct StoreQuality<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+
+    // Required when the subscription is represented by a pass
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.is_held_by(&subscriber.key(), pass_token_account.as_deref())
+            @ SubscriptionError::NotSubscriptionHolder
+    )]
+    pub subscription: Account<'info, Subscription>,
+
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+
+    #[account(
+        init,
+        payer = subscriber,
+        space = 8 + RatingEvidence::SIZE,
+        seeds = [b"evidence", data_provider.key().as_ref(), &quality_info.rating_count.to_le_bytes()],
+        bump
+    )]
+    pub evidence: Option<Account<'info, RatingEvidence>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateSubscribersList<'info> {
+    /// CHECK: Data provider account is just used as a seed for PDA
+    pub data_provider: UncheckedAccount<'info>,
+
+    /// CHECK: Original-layout SubscribersList, checked and rewritten by the handler
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"subscribers", data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscribers_list: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateQualityInfo<'info> {
+    /// CHECK: Data provider account is just used as a seed for PDA
+    pub data_provider: UncheckedAccount<'info>,
+
+    /// CHECK: v1 QualityInfo, checked and rewritten by the handler
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump
+    )]
+    pub quality_info: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    /// CHECK: State in the original layout, checked and rewritten by the handler
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"storage"],
+        bump
+    )]
+    pub state: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateSubscription<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+
+    /// CHECK: Data provider account is just used as a seed for PDA
+    pub data_provider: UncheckedAccount<'info>,
+
+    /// CHECK: Subscription in the original layout, checked and rewritten by the handler
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"subscription", subscriber.key().as_ref(), data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscription: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeQualityInfo<'info> {
+    #[account(
+        init_if_needed,
+        payer = payer,
+        // QualityInfo::SIZE already includes the discriminator
+        space = QualityInfo::SIZE,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+
+    /// CHECK: Data provider account is just used as a seed for PDA
+    pub data_provider: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterProvider<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    #[account(
+        init,
+        payer = data_provider,
+        space = 8 + ProviderConfig::SIZE,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+    #[account(
+        init,
+        payer = data_provider,
+        space = 8,
+        seeds = [b"stake_vault", data_provider.key().as_ref()],
+        bump
+    )]
+    pub stake_vault: Account<'info, StakeVault>,
+    pub system_program: Program<'info, System>,
+}
+
+// Context struct for provider-only settings
+#[derive(Accounts)]
+pub struct ProviderFunction<'info> {
+    pub data_provider: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ManageStake<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+    #[account(
+        mut,
+        seeds = [b"stake_vault", data_provider.key().as_ref()],
+        bump
+    )]
+    pub stake_vault: Account<'info, StakeVault>,
+}
+
+#[derive(Accounts)]
+pub struct SlashProvider<'info> {
+    pub state: Account<'info, State>,
+    /// CHECK: Data provider being slashed
+    pub data_provider: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+    #[account(
+        mut,
+        seeds = [b"stake_vault", data_provider.key().as_ref()],
+        bump
+    )]
+    pub stake_vault: Account<'info, StakeVault>,
+    #[account(
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+}
+
+#[derive(Accounts)]
+#[instruction(rating_id: u64)]
+pub struct DisputeRating<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    #[account(
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    #[account(
+        init,
+        payer = data_provider,
+        space = 8 + Dispute::SIZE,
+        seeds = [b"dispute", data_provider.key().as_ref(), &rating_id.to_le_bytes()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveDispute<'info> {
+    pub state: Account<'info, State>,
+    #[account(constraint = arbiter.key() == state.arbiter @ SubscriptionError::NotArbiter)]
+    pub arbiter: Signer<'info>,
+    /// CHECK: Provider that opened the dispute, receives the account rent and any refunded bond
+    #[account(mut, address = dispute.data_provider)]
+    pub data_provider: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"quality", data_provider.key().as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    #[account(
+        mut,
+        close = data_provider,
+        seeds = [b"dispute", dispute.data_provider.as_ref(), &dispute.rating_id.to_le_bytes()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+    /// CHECK: Treasury (the contract owner) that receives forfeited bonds
+    #[account(mut, address = state.owner)]
+    pub treasury: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(coupon_id: u64)]
+pub struct CreateCoupon<'info> {
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    #[account(
+        init,
+        payer = data_provider,
+        space = 8 + Coupon::SIZE,
+        seeds = [b"coupon", data_provider.key().as_ref(), &coupon_id.to_le_bytes()],
+        bump
+    )]
+    pub coupon: Account<'info, Coupon>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TerminateSubscription<'info> {
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump
+    )]
+    pub subscription: Account<'info, Subscription>,
+    // Required when the subscription is represented by a pass; its holder is refunded
+    pub pass_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(
+        address = subscription.payment_mint @ SubscriptionError::PaymentMintMismatch,
+        mint::token_program = token_program
+    )]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &data_provider_payment_ata,
+            &data_provider.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidProviderPaymentAccount
+    )]
+    pub data_provider_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = subscription
+            .refund_recipient_for(pass_token_account.as_deref())
+            .is_some_and(|refund_recipient| is_associated_token_account(
+                &refund_ata,
+                &refund_recipient,
+                &payment_mint.key(),
+                &token_program.key(),
+            )) @ SubscriptionError::InvalidRefundAccount
+    )]
+    pub refund_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct TerminateLegacySubscription<'info> {
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"subscription", subscription.subscriber.as_ref(), data_provider.key().as_ref()],
+        bump,
+        constraint = subscription.pays_in_lamports() @ SubscriptionError::PaymentMintMismatch
+    )]
+    pub subscription: Account<'info, Subscription>,
+    /// CHECK: Wallet refunds go to
+    #[account(mut, address = subscription.refund_recipient() @ SubscriptionError::InvalidRefundAccount)]
+    pub refund_recipient: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageBlocklist<'info> {
+    #[account(mut)]
+    pub data_provider: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = data_provider,
+        space = 8 + Blocklist::SIZE,
+        seeds = [b"blocklist", data_provider.key().as_ref()],
+        bump
+    )]
+    pub blocklist: Account<'info, Blocklist>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinWaitlist<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account; receives forfeited deposits
+    #[account(mut)]
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    #[account(
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: Account<'info, ProviderConfig>,
+    #[account(
+        init_if_needed,
+        payer = subscriber,
+        space = 8 + Waitlist::SIZE,
+        seeds = [b"waitlist", data_provider.key().as_ref()],
+        bump
+    )]
+    pub waitlist: Account<'info, Waitlist>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LeaveWaitlist<'info> {
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"waitlist", data_provider.key().as_ref()],
+        bump
+    )]
+    pub waitlist: Account<'info, Waitlist>,
+}
+
+#[derive(Accounts)]
+pub struct SubscribeBundle<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub subscriber: Signer<'info>,
+    #[account(mint::token_program = token_program)]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"accepted_mint", payment_mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Option<Account<'info, AcceptedMint>>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &subscriber_payment_ata,
+            &subscriber.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidSubscriberPaymentAccount
+    )]
+    pub subscriber_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &owner_payment_ata,
+            &state.owner,
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidOwnerPaymentAccount
+    )]
+    pub owner_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub system_program: Program<'info, System>,
+    // Payment token program, either the legacy token program or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(group_id: u64)]
+pub struct SubscribeGroup<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// CHECK: Data provider account
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    /// CHECK: Provider's QualityInfo in either layout; created or migrated by the handler
+    #[account(mut, seeds = [b"quality", data_provider.key().as_ref()], bump)]
+    pub quality_info: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GroupSubscription::SIZE,
+        seeds = [b"group", admin.key().as_ref(), data_provider.key().as_ref(), &group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    /// CHECK: Provider's subscriber registry in either layout; created or migrated by the handler
+    #[account(mut, seeds = [b"subscribers", data_provider.key().as_ref()], bump)]
+    pub subscribers_list: UncheckedAccount<'info>,
+    #[account(mint::token_program = token_program)]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"accepted_mint", payment_mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Option<Account<'info, AcceptedMint>>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &data_provider_payment_ata,
+            &data_provider.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidProviderPaymentAccount
+    )]
+    pub data_provider_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &admin_payment_ata,
+            &admin.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidSubscriberPaymentAccount
+    )]
+    pub admin_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &owner_payment_ata,
+            &state.owner,
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidOwnerPaymentAccount
+    )]
+    pub owner_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub system_program: Program<'info, System>,
+    // Payment token program, either the legacy token program or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+    // NFT check uses the legacy token program
+    pub nft_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    /// CHECK: Provider's waitlist; read only once someone has queued
+    #[account(mut, seeds = [b"waitlist", data_provider.key().as_ref()], bump)]
+    pub waitlist: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddGroupMember<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"group", admin.key().as_ref(), group.data_provider.as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    /// CHECK: Wallet receiving the seat
+    pub member: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        seeds = [b"provider", group.data_provider.as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", group.data_provider.as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GroupMembership::SIZE,
+        seeds = [b"member", group.key().as_ref(), member.key().as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, GroupMembership>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveGroupMember<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"group", admin.key().as_ref(), group.data_provider.as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    /// CHECK: Wallet losing the seat
+    pub member: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"member", group.key().as_ref(), member.key().as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, GroupMembership>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMemberRecipient<'info> {
+    pub member: Signer<'info>,
+    #[account(
+        seeds = [b"group", group.admin.as_ref(), group.data_provider.as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    #[account(
+        mut,
+        seeds = [b"member", group.key().as_ref(), member.key().as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, GroupMembership>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        seeds = [b"provider", group.data_provider.as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RateGroupSubscription<'info> {
+    #[account(mut)]
+    pub member: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"group", group.admin.as_ref(), group.data_provider.as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    #[account(
+        mut,
+        seeds = [b"member", group.key().as_ref(), member.key().as_ref()],
+        bump
+    )]
+    pub membership: Account<'info, GroupMembership>,
+    #[account(
+        mut,
+        seeds = [b"quality", group.data_provider.as_ref()],
+        bump,
+        constraint = quality_info.version == QUALITY_INFO_VERSION @ SubscriptionError::QualityInfoNotMigrated
+    )]
+    pub quality_info: Account<'info, QualityInfo>,
+    #[account(
+        init,
+        payer = member,
+        space = 8 + RatingEvidence::SIZE,
+        seeds = [b"evidence", group.data_provider.as_ref(), &quality_info.rating_count.to_le_bytes()],
+        bump
+    )]
+    pub evidence: Option<Account<'info, RatingEvidence>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RenewGroupSubscription<'info> {
+    pub state: Account<'info, State>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// CHECK: Data provider account
+    #[account(address = group.data_provider)]
+    pub data_provider: UncheckedAccount<'info>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", data_provider.key().as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+    /// CHECK: Provider's QualityInfo in either layout; created or migrated by the handler
+    #[account(mut, seeds = [b"quality", data_provider.key().as_ref()], bump)]
+    pub quality_info: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"group", admin.key().as_ref(), data_provider.key().as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    #[account(
+        mint::token_program = token_program,
+        constraint = payment_mint.key() == group.payment_mint @ SubscriptionError::PaymentMintMismatch
+    )]
+    pub payment_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"accepted_mint", payment_mint.key().as_ref()],
+        bump
+    )]
+    pub accepted_mint: Option<Account<'info, AcceptedMint>>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &data_provider_payment_ata,
+            &data_provider.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidProviderPaymentAccount
+    )]
+    pub data_provider_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &admin_payment_ata,
+            &admin.key(),
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidSubscriberPaymentAccount
+    )]
+    pub admin_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    #[account(
+        mut,
+        constraint = is_associated_token_account(
+            &owner_payment_ata,
+            &state.owner,
+            &payment_mint.key(),
+            &token_program.key(),
+        ) @ SubscriptionError::InvalidOwnerPaymentAccount
+    )]
+    pub owner_payment_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+    pub system_program: Program<'info, System>,
+    // Payment token program, either the legacy token program or Token-2022
+    pub token_program: Interface<'info, TokenInterface>,
+    // NFT check uses the legacy token program
+    pub nft_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Provider's blocklist; read only once the provider has banned someone
+    #[account(seeds = [b"blocklist", data_provider.key().as_ref()], bump)]
+    pub blocklist: UncheckedAccount<'info>,
+    /// CHECK: Provider's waitlist; read only once someone has queued
+    #[account(mut, seeds = [b"waitlist", data_provider.key().as_ref()], bump)]
+    pub waitlist: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EndGroupSubscription<'info> {
+    #[account(
+        mut,
+        seeds = [b"group", group.admin.as_ref(), group.data_provider.as_ref(), &group.group_id.to_le_bytes()],
+        bump
+    )]
+    pub group: Account<'info, GroupSubscription>,
+    /// CHECK: Provider's config; empty while the provider is unregistered
+    #[account(
+        mut,
+        seeds = [b"provider", group.data_provider.as_ref()],
+        bump
+    )]
+    pub provider_config: UncheckedAccount<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct QualityRecord {
+    pub rater: Pubkey,
+    pub ratings: QualityRatings,
+    pub weight: u64,        // Seconds of subscription the rater paid for in the rated period
+    pub timestamp: i64,     // When the rating was recorded
+    pub slot: u64,
+    pub period_start: i64,  // Subscription period the rating covers
+    pub period_end: i64,
+    pub score_bps: Option<u64>, // Provider's reputation score right after this rating
+}
+
+impl QualityRecord {
+    pub const SIZE: usize = 32 + QualityRatings::SIZE + 8 + 8 + 8 + 8 + 8 + 9;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct QualityRecordV1 {
+    pub provider: Pubkey, // Actually the rater
+    pub quality: u8,
+}
+
+impl QualityRecordV1 {
+    pub const SIZE: usize = 32 + 1;
+}
+
+// Where the provider delivers a subscription's data
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum DeliveryTarget {
+    Pubkey(Pubkey),      // Solana account
+    Webhook(String),     // HTTPS URL, at most MAX_WEBHOOK_URL_LEN bytes
+    AgentId(String),     // At most MAX_AGENT_ID_LEN bytes
+    Encrypted(Vec<u8>),  // Opaque blob, at most MAX_ENCRYPTED_TARGET_LEN bytes
+}
+
+impl DeliveryTarget {
+    // Enum tag + the largest variant
+    pub const SIZE: usize = 1 + max_len(
+        max_len(32, 4 + MAX_WEBHOOK_URL_LEN),
+        max_len(4 + MAX_AGENT_ID_LEN, 4 + MAX_ENCRYPTED_TARGET_LEN),
+    );
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            DeliveryTarget::Pubkey(_) => {}
+            DeliveryTarget::Webhook(url) => {
+                require!(
+                    url.len() <= MAX_WEBHOOK_URL_LEN
+                        && url.starts_with("https://")
+                        && url.len() > "https://".len(),
+                    SubscriptionError::InvalidDeliveryTarget
+                );
+            }
+            DeliveryTarget::AgentId(agent_id) => {
+                require!(
+                    !agent_id.is_empty() && agent_id.len() <= MAX_AGENT_ID_LEN,
+                    SubscriptionError::InvalidDeliveryTarget
+                );
+            }
+            DeliveryTarget::Encrypted(blob) => {
+                require!(
+                    !blob.is_empty() && blob.len() <= MAX_ENCRYPTED_TARGET_LEN,
+                    SubscriptionError::InvalidDeliveryTarget
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Best-effort reading of a free-form recipient from the original layout.
+    // Empty recipients deliver to the subscriber's wallet
+    pub fn from_legacy(recipient: String, subscriber: Pubkey) -> Self {
+        if recipient.is_empty() {
+            return DeliveryTarget::Pubkey(subscriber);
+        }
+        if let Ok(pubkey) = recipient.parse::<Pubkey>() {
+            return DeliveryTarget::Pubkey(pubkey);
+        }
+        let webhook = DeliveryTarget::Webhook(recipient.clone());
+        if webhook.validate().is_ok() {
+            webhook
+        } else {
+            DeliveryTarget::AgentId(recipient)
+        }
     }
 
-    pub fn initialize_quality_info(ctx: Context<InitializeQualityInfo>) -> Result<()> {
-        let quality_info = &mut ctx.accounts.quality_info;
-        quality_info.current_index = 0;
-        quality_info.subscriber = Pubkey::default();
-        quality_info.quality = 0;
+    pub fn serialized_len(&self) -> usize {
+        1 + match self {
+            DeliveryTarget::Pubkey(_) => 32,
+            DeliveryTarget::Webhook(value) | DeliveryTarget::AgentId(value) => 4 + value.len(),
+            DeliveryTarget::Encrypted(blob) => 4 + blob.len(),
+        }
+    }
 
-        // Initialize the qualities vector with default values
-        quality_info.qualities = vec![
-            QualityRecord {
-                provider: Pubkey::default(),
-                quality: 0
-            };  // (Pubkey, quality) default pair
-            MAX_QUALITY_RECORDS      // Create MAX_QUALITY_RECORDS number of entries
-        ];
+    // The target as events carry it, so the provider can deliver without
+    // reading the subscription account. Plaintext endpoints stay off the
+    // event log and are only hashed; providers read them from the account.
+    pub fn emitted(&self) -> Option<DeliveryTarget> {
+        match self {
+            DeliveryTarget::Pubkey(_) | DeliveryTarget::Encrypted(_) => Some(self.clone()),
+            DeliveryTarget::Webhook(_) | DeliveryTarget::AgentId(_) => None,
+        }
+    }
 
-        Ok(())
+    // Emitted in place of the target so events never carry the endpoint.
+    // Salted with the subscription's address so common endpoints can't be
+    // recovered from a precomputed table or linked across subscriptions.
+    pub fn hash(&self, subscription: &Pubkey) -> [u8; 32] {
+        anchor_lang::solana_program::hash::hashv(&[
+            subscription.as_ref(),
+            &self.try_to_vec().unwrap_or_default(),
+        ])
+        .to_bytes()
+    }
+}
+
+impl Default for DeliveryTarget {
+    fn default() -> Self {
+        DeliveryTarget::Pubkey(Pubkey::default())
     }
+}
 
+// One of a subscription's delivery destinations
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Recipient {
+    pub label: Option<String>,  // At most MAX_RECIPIENT_LABEL_LEN bytes
+    pub target: DeliveryTarget,
 }
 
-// Account structures
-#[account]
-pub struct State {
-    pub owner: Pubkey,
-    pub nft_program_id: Pubkey,
-    pub payment_spl_token: Pubkey,
+impl Recipient {
+    pub const SIZE: usize = 1 + 4 + MAX_RECIPIENT_LABEL_LEN + DeliveryTarget::SIZE;
+
+    pub fn serialized_len(&self) -> usize {
+        1 + self.label.as_ref().map_or(0, |label| 4 + label.len()) + self.target.serialized_len()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(label) = &self.label {
+            require!(
+                label.len() <= MAX_RECIPIENT_LABEL_LEN,
+                SubscriptionError::RecipientLabelTooLong
+            );
+        }
+        self.target.validate()
+    }
+
+    // `subscription` is the account holding the recipient, which salts the hash
+    pub fn summary(&self, subscription: &Pubkey) -> RecipientSummary {
+        RecipientSummary {
+            label: self.label.clone(),
+            target_hash: self.target.hash(subscription),
+            target: self.target.emitted(),
+        }
+    }
+
+    pub fn summarize(recipients: &[Recipient], subscription: &Pubkey) -> Vec<RecipientSummary> {
+        recipients
+            .iter()
+            .map(|recipient| recipient.summary(subscription))
+            .collect()
+    }
+}
+
+// Event form of a recipient; the target itself is never emitted
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RecipientSummary {
+    pub label: Option<String>,
+    pub target_hash: [u8; 32],
+    pub target: Option<DeliveryTarget>, // See DeliveryTarget::emitted
+}
+
+// Entry in a provider's subscriber registry
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SubscriberEntry {
+    pub subscriber: Pubkey,  // Subscriber, or the GroupSubscription account for groups
+    pub seats: u32,          // 1 for individual subscriptions
+}
+
+impl SubscriberEntry {
+    pub const SIZE: usize = 32 + 4;
+}
+
+const fn max_len(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+// Ratings for each quality dimension, 0-100
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct QualityRatings {
+    pub accuracy: u8,
+    pub latency: u8,
+    pub uptime: u8,
+    pub completeness: u8,
+}
+
+impl QualityRatings {
+    pub const SIZE: usize = 4;
+
+    pub fn is_valid(&self) -> bool {
+        self.accuracy <= 100
+            && self.latency <= 100
+            && self.uptime <= 100
+            && self.completeness <= 100
+    }
+}
+
+// Running aggregates for one quality dimension
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct QualityStats {
+    pub sum: u64,
+    pub sum_sq: u64,
+    pub ema: u64,           // In basis points of the 0-100 scale
+    pub weighted_sum: u64,
+    pub weighted_sum_sq: u64,
+}
+
+impl QualityStats {
+    pub const SIZE: usize = 8 * 5;
+
+    // Weights are capped at FULL_WEIGHT_PERIOD, but records written before
+    // the cap aren't, so the weighted products are taken in u128 and the sums
+    // saturate instead of panicking
+    fn record(&mut self, value: u8, weight: u64, alpha_bps: u64) {
+        let value = value as u64;
+        self.ema = (self.ema * (BPS_DENOMINATOR - alpha_bps) + value * 100 * alpha_bps)
+            / BPS_DENOMINATOR;
+        self.sum = self.sum.saturating_add(value);
+        self.sum_sq = self.sum_sq.saturating_add(value * value);
+        self.weighted_sum = self.weighted_sum.saturating_add(weighted(value, weight));
+        self.weighted_sum_sq = self.weighted_sum_sq.saturating_add(weighted(value * value, weight));
+    }
+
+    fn remove(&mut self, value: u8, weight: u64) {
+        let value = value as u64;
+        self.sum -= value;
+        self.sum_sq -= value * value;
+        self.weighted_sum = self.weighted_sum.saturating_sub(weighted(value, weight));
+        self.weighted_sum_sq = self.weighted_sum_sq.saturating_sub(weighted(value * value, weight));
+    }
+}
+
+fn weighted(value: u64, weight: u64) -> u64 {
+    u64::try_from(value as u128 * weight as u128).unwrap_or(u64::MAX)
+}
+
+// Admin-configured collateral and slashing rules
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct SlashingParams {
+    pub min_provider_stake: u64,   // Lamports required to register
+    pub threshold_bps: u64,        // Reputation score below which providers can be slashed
+    pub slash_window: u64,         // Ratings required between slashes
+    pub slash_fraction_bps: u16,   // Share of the stake slashed each time
+    pub withdrawal_cooldown: i64,  // Seconds between requesting and withdrawing stake
+}
+
+impl SlashingParams {
+    pub const SIZE: usize = 8 + 8 + 8 + 2 + 8;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PriceCapPoint {
+    pub min_score_bps: u64,    // Reputation score needed for this cap
+    pub max_fee_per_day: u64,  // Highest price per day allowed at this score
+}
+
+// Off-chain evidence supplied with a rating
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RatingEvidenceArgs {
+    pub content_hash: [u8; 32],
+    pub uri: String,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct VolumeDiscount {
+    pub min_duration: i64,  // Subscription length in seconds to qualify
+    pub discount_bps: u16,  // Discount on the provider fee in basis points
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ProviderReputation {
+    pub rating_count: u64,
+    pub struck_count: u64,
+    pub total_weight: u64,
+    pub accuracy: QualityStats,
+    pub latency: QualityStats,
+    pub uptime: QualityStats,
+    pub completeness: QualityStats,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SubscriptionQuote {
+    pub duration: i64,
     pub fee_per_day: u64,
+    pub provider_fee: u64,
+    pub volume_discount_bps: u16,
     pub collector_fee: u64,
 }
 
-impl State {
-    pub const SIZE: usize = 32 + 32 + 8 + 8; // 2 Pubkeys (32 bytes each) + 2 u64s (8 bytes each)
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum CouponDiscount {
+    Percent(u8),  // Percentage off the provider fee (0-100)
+    Fixed(u64),   // Flat amount off the provider fee
 }
 
-#[account]
-pub struct Subscription {
+
+// Events
+#[event]
+pub struct SubscriptionCreatedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub payer: Pubkey,
+    pub pass_mint: Option<Pubkey>,
+    pub recipients: Vec<RecipientSummary>,
     pub end_time: i64,
-    pub recipient: String,
+    pub timestamp: i64,
+    pub coupon_id: Option<u64>,
+    pub volume_discount_bps: u16,
+    pub payment_mint: Pubkey,
+}
+
+#[event]
+pub struct SubscriptionRenewedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub new_recipients: Vec<RecipientSummary>,
+    pub new_end_time: i64,
+    pub timestamp: i64,
+    pub coupon_id: Option<u64>,
+    pub volume_discount_bps: u16,
+    pub payment_mint: Pubkey,
+}
+
+#[event]
+pub struct RecipientUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub index: u8,
+    pub label: Option<String>,
+    pub recipient_hash: [u8; 32],  // Hash of the delivery target
+    pub target: Option<DeliveryTarget>, // See DeliveryTarget::emitted
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RecipientAddedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub index: u8,
+    pub label: Option<String>,
+    pub recipient_hash: [u8; 32],  // Hash of the delivery target
+    pub target: Option<DeliveryTarget>, // See DeliveryTarget::emitted
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RecipientRemovedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub index: u8,
+    pub label: Option<String>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionTerminatedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub reason_code: u16,          // Provider-defined reason
+    pub refund: u64,               // In the subscription's payment mint
+    pub refund_recipient: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriberBannedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+}
+
+#[event]
+pub struct SubscriberUnbannedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+}
+
+#[event]
+pub struct SubscriptionCancelledEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+}
+
+#[event]
+pub struct SubscriptionEndedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+}
+
+#[event]
+pub struct SubscriptionReleasedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub active_subscriptions: u32,
+}
+
+#[event]
+pub struct BundlePurchasedEvent {
+    pub subscriber: Pubkey,
+    pub providers: Vec<Pubkey>,
+    pub end_time: i64,
+    pub timestamp: i64,
+    pub bundle_discount_bps: u16,
+    pub total_provider_fee: u64,  // In the payment mint, across all providers
+    pub collector_fee: u64,
+    pub payment_mint: Pubkey,
+}
+
+#[event]
+pub struct GroupSubscriptionCreatedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
+    pub admin: Pubkey,
+    pub group_id: u64,
+    pub seats: u32,
+    pub end_time: i64,
+    pub timestamp: i64,
+    pub volume_discount_bps: u16,
+    pub payment_mint: Pubkey,
+}
+
+#[event]
+pub struct GroupMemberAddedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
+    pub member: Pubkey,
+    pub members: u32,  // Seats in use after the change
+    pub recipient: RecipientSummary,
+}
+
+#[event]
+pub struct GroupMemberRecipientUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
+    pub member: Pubkey,
+    pub recipient: RecipientSummary,
 }
 
-impl Subscription {
-    pub const SIZE: usize = 8 + 32; // i64 (8 bytes) + String (estimated 32 bytes)
+#[event]
+pub struct GroupMemberRemovedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
+    pub member: Pubkey,
+    pub members: u32,  // Seats in use after the change
 }
 
-#[account]
-pub struct SubscribersList {
-    pub subscribers: Vec<Pubkey>,
+#[event]
+pub struct GroupSubscriptionRenewedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
+    pub seats: u32,
+    pub new_end_time: i64,
+    pub timestamp: i64,
+    pub volume_discount_bps: u16,
+    pub payment_mint: Pubkey,
 }
 
-impl SubscribersList {
-    pub const SIZE: usize = 4 + (32 * 10); // Vec length (4 bytes) + space for 10 Pubkeys (32 bytes each)
+#[event]
+pub struct GroupSubscriptionEndedEvent {
+    pub data_provider: Pubkey,
+    pub group: Pubkey,
 }
 
-#[account]
-#[derive(Default)]
-pub struct QualityInfo {
-    pub subscriber: Pubkey,          // 32 bytes
-    pub quality: u8,                 // 1 byte
-    pub current_index: u8,           // 1 byte
-    pub qualities: Vec<QualityRecord> // Using our new struct instead of tuple
+#[event]
+pub struct FeePerDayUpdatedEvent {
+    pub new_fee_per_day: u64,
 }
 
-impl QualityInfo {
-    pub const MAX_QUALITY_RECORDS: usize = 10; // Define max records for space calculation
+#[event]
+pub struct CollectorFeeUpdatedEvent {
+    pub new_collector_fee: u64,
+}
 
-    pub const SIZE: usize = 8 +  // discriminator
-                           32 +  // subscriber pubkey
-                           1 +   // quality
-                           1 +   // current_index
-                           4 +   // vec length
-                           (Self::MAX_QUALITY_RECORDS * (32 + 1)); // qualities array size
+#[event]
+pub struct ArbiterUpdatedEvent {
+    pub new_arbiter: Pubkey,
 }
 
-// Context structs for instructions
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(
-        init,
-        payer = owner,
-        space = 8 + State::SIZE,
-        seeds = [b"storage"],
-        bump
-    )]
-    pub state: Account<'info, State>,
-    #[account(mut)]
-    pub owner: Signer<'info>,
-    /// CHECK: This is the NFT program ID
-    pub nft_program: UncheckedAccount<'info>,
-    /// CHECK: SPL token account for payment
-    pub payment_spl_token: UncheckedAccount<'info>,
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct BundleDiscountUpdatedEvent {
+    pub new_bundle_discount_bps: u16,
 }
 
-#[derive(Accounts)]
-pub struct Subscribe<'info> {
-    #[account(mut)]
-    pub state: Account<'info, State>,
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
-    /// CHECK: Data provider account
-    #[account(mut)]
-    pub data_provider: UncheckedAccount<'info>,
-    #[account(
-        init,
-        payer = subscriber,
-        space = 8 + Subscription::SIZE,
-        seeds = [b"subscription", subscriber.key().as_ref(), data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscription: Account<'info, Subscription>,
-    #[account(
-        init_if_needed,
-        payer = subscriber,
-        space = 8 + SubscribersList::SIZE,
-        seeds = [b"subscribers", data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscribers_list: Account<'info, SubscribersList>,
-    /// CHECK: Owner account from state
-    #[account(mut, constraint = owner.key() == state.owner)]
-    pub owner: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub data_provider_payment_ata: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub subscriber_payment_ata: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub owner_payment_ata: Account<'info, TokenAccount>,
-    pub system_program: Program<'info, System>,
-    // Add token program and token account validations for NFT check
-    pub token_program: Program<'info, Token>,
-    pub nft_token_account: Account<'info, TokenAccount>,
+#[event]
+pub struct DisputeBondUpdatedEvent {
+    pub new_dispute_bond: u64,
 }
 
-#[derive(Accounts)]
-pub struct RenewSubscription<'info> {
-    #[account(mut)]
-    pub state: Account<'info, State>,
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
-    /// CHECK: Data provider account
-    #[account(mut)]
-    pub data_provider: UncheckedAccount<'info>,
-    #[account(
-        mut,
-        seeds = [b"subscription", subscriber.key().as_ref(), data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscription: Account<'info, Subscription>,
-    #[account(
-        mut,
-        seeds = [b"quality", data_provider.key().as_ref()],
-        bump
-    )]
-    pub quality_info: Account<'info, QualityInfo>,
-    /// CHECK: Owner account from state
-    #[account(mut, constraint = owner.key() == state.owner)]
-    pub owner: UncheckedAccount<'info>,
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub nft_token_account: Account<'info, TokenAccount>,
+#[event]
+pub struct RatingDisputedEvent {
+    pub data_provider: Pubkey,
+    pub rating_id: u64,
+    pub rater: Pubkey,
+    pub response_hash: [u8; 32],
+    pub bond: u64,
 }
 
-#[derive(Accounts)]
-pub struct CancelSubscription<'info> {
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
-    /// CHECK: Data provider account
-    pub data_provider: UncheckedAccount<'info>,
-    #[account(
-        mut,
-        seeds = [b"subscription", subscriber.key().as_ref(), data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscription: Account<'info, Subscription>,
-    #[account(
-        mut,
-        seeds = [b"quality", data_provider.key().as_ref()],
-        bump
-    )]
-    pub quality_info: Account<'info, QualityInfo>,
-    pub token_program: Program<'info, Token>,
-    pub nft_token_account: Account<'info, TokenAccount>,
+#[event]
+pub struct DisputeResolvedEvent {
+    pub data_provider: Pubkey,
+    pub rating_id: u64,
+    pub upheld: bool,
+    pub bond: u64,
 }
 
-#[derive(Accounts)]
-pub struct EndSubscription<'info> {
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
-    /// CHECK: Data provider account
-    pub data_provider: UncheckedAccount<'info>,
-    #[account(
-        mut,
-        seeds = [b"subscription", subscriber.key().as_ref(), data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscription: Account<'info, Subscription>,
-    #[account(
-        mut,
-        seeds = [b"quality", data_provider.key().as_ref()],
-        bump
-    )]
-    pub quality_info: Account<'info, QualityInfo>,
+#[event]
+pub struct SlashingParamsUpdatedEvent {
+    pub params: SlashingParams,
 }
 
-#[derive(Accounts)]
-pub struct GetSubscribers<'info> {
-    /// CHECK: Data provider account
-    pub data_provider: UncheckedAccount<'info>,
-    #[account(
-        seeds = [b"subscribers", data_provider.key().as_ref()],
-        bump
-    )]
-    pub subscribers_list: Account<'info, SubscribersList>,
+#[event]
+pub struct ProviderRegisteredEvent {
+    pub data_provider: Pubkey,
+    pub stake: u64,
 }
 
-// Context struct for admin functions
-#[derive(Accounts)]
-pub struct AdminFunction<'info> {
-    #[account(
-        mut,
-        constraint = state.owner == owner.key() @ SubscriptionError::NotOwner
-    )]
-    pub state: Account<'info, State>,
+#[event]
+pub struct StakeWithdrawalRequestedEvent {
+    pub data_provider: Pubkey,
+    pub available_at: i64,
+}
 
-    #[account(
-        signer,
-        constraint = owner.key() == state.owner @ SubscriptionError::NotOwner
-    )]
-    pub owner: Signer<'info>,
+#[event]
+pub struct StakeWithdrawnEvent {
+    pub data_provider: Pubkey,
+    pub amount: u64,
+    pub remaining_stake: u64,
 }
 
-#[derive(Accounts)]
-pub struct StoreDataQuality<'info> {
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
-    /// CHECK: Data provider account
-    pub data_provider: AccountInfo<'info>,
-    #[account(mut, seeds = [b"quality", data_provider.key().as_ref()], bump)]
-    pub quality_info: Account<'info, QualityInfo>,
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+#[event]
+pub struct ProviderSlashedEvent {
+    pub data_provider: Pubkey,
+    pub amount: u64,
+    pub subscribers: u32,
+    pub remaining_stake: u64,
 }
 
-// Context struct for storing quality data
-#[derive(Accounts)]
-pub struct StoreQuality<'info> {
-    #[account(mut)]
-    pub subscriber: Signer<'info>,
+#[event]
+pub struct PriceCapsUpdatedEvent {
+    pub points: Vec<PriceCapPoint>,
+}
 
-    /// CHECK: Data provider account
-    pub data_provider: UncheckedAccount<'info>,
+#[event]
+pub struct ProviderFeePerDayUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub new_fee_per_day: u64,
+}
 
-    #[account(
-        mut,
-        seeds = [b"quality", data_provider.key().as_ref()],
-        bump
-    )]
-    pub quality_info: Account<'info, QualityInfo>,
+#[event]
+pub struct ProviderMaxSubscribersUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub max_subscribers: u32,
 }
 
-#[derive(Accounts)]
-pub struct InitializeQualityInfo<'info> {
-    #[account(
-        init,
-        payer = payer,
-        // Calculate space for: discriminator + subscriber pubkey + quality + current_index + qualities vec
-        space = 8 + 32 + 1 + 1 + 4 + (MAX_QUALITY_RECORDS * (32 + 1)),
-        seeds = [b"quality", data_provider.key().as_ref()],
-        bump
-    )]
-    pub quality_info: Account<'info, QualityInfo>,
+#[event]
+pub struct WaitlistJoinedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub position: u32,  // 0 is the front of the queue
+    pub deposit: u64,
+    pub expires_at: i64,
+}
 
-    /// CHECK: Data provider account is just used as a seed for PDA
-    pub data_provider: UncheckedAccount<'info>,
+#[event]
+pub struct WaitlistEntryExpiredEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub forfeited_deposit: u64,
+}
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
+#[event]
+pub struct WaitlistLeftEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+}
 
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct ProviderMaxRecipientsUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub max_recipients: u8,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
-pub struct QualityRecord {
-    pub provider: Pubkey,
-    pub quality: u8,
+#[event]
+pub struct ProviderEncryptionKeyUpdatedEvent {
+    pub data_provider: Pubkey,
+    pub encryption_key: [u8; 32],
 }
 
+#[event]
+pub struct VolumeDiscountsUpdatedEvent {
+    pub tiers: Vec<VolumeDiscount>,
+}
 
-// Events
 #[event]
-pub struct SubscriptionCreatedEvent {
-    pub data_provider: Pubkey,
-    pub subscriber: Pubkey,
-    pub recipient: String,
-    pub end_time: i64,
-    pub timestamp: i64,
+pub struct AcceptedMintUpdatedEvent {
+    pub mint: Pubkey,
+    pub conversion_rate: u64,
 }
 
 #[event]
-pub struct SubscriptionRenewedEvent {
+pub struct AcceptedMintRemovedEvent {
+    pub mint: Pubkey,
+}
+
+#[event]
+pub struct CouponCreatedEvent {
     pub data_provider: Pubkey,
-    pub subscriber: Pubkey,
-    pub new_recipient: String,
-    pub new_end_time: i64,
-    pub timestamp: i64,
+    pub coupon_id: u64,
+    pub discount: CouponDiscount,
+    pub expires_at: i64,
+    pub max_redemptions: u32,
 }
 
 #[event]
-pub struct SubscriptionCancelledEvent {
+pub struct SubscribersListMigratedEvent {
     pub data_provider: Pubkey,
-    pub subscriber: Pubkey,
+    pub subscribers: u32,
 }
 
 #[event]
-pub struct SubscriptionEndedEvent {
+pub struct QualityInfoMigratedEvent {
     pub data_provider: Pubkey,
-    pub subscriber: Pubkey,
+    pub version: u8,
 }
 
 #[event]
-pub struct FeePerDayUpdatedEvent {
-    pub new_fee_per_day: u64,
+pub struct SubscriptionMigratedEvent {
+    pub data_provider: Pubkey,
+    pub subscriber: Pubkey,
+    pub version: u8,
 }
 
 #[event]
-pub struct CollectorFeeUpdatedEvent {
-    pub new_collector_fee: u64,
+pub struct StateMigratedEvent {
+    pub owner: Pubkey,
 }
 
 #[event]
 pub struct QualityProvidedEvent {
     pub data_provider: Pubkey,
     pub subscriber: Pubkey,
-    pub quality: u8,
+    pub ratings: QualityRatings,
+    pub weight: u64,
+    pub rating_id: u64,
+    pub content_hash: Option<[u8; 32]>,
+    pub evidence_uri: Option<String>,
 }
 
 // Error definitions
@@ -708,4 +5690,1167 @@ pub enum SubscriptionError {
     ActiveSubscription,
     #[msg("Not the contract owner")]
     NotOwner,
+    #[msg("Coupon does not belong to this data provider")]
+    InvalidCoupon,
+    #[msg("Coupon discount is out of range")]
+    InvalidCouponDiscount,
+    #[msg("Coupon whitelist is too long")]
+    CouponWhitelistTooLong,
+    #[msg("Coupon has expired")]
+    CouponExpired,
+    #[msg("Coupon has no redemptions left")]
+    CouponExhausted,
+    #[msg("Subscriber is not whitelisted for this coupon")]
+    CouponNotWhitelisted,
+    #[msg("Too many volume discount tiers")]
+    TooManyVolumeDiscountTiers,
+    #[msg("Invalid volume discount tier")]
+    InvalidVolumeDiscount,
+    #[msg("Payment mint is not accepted")]
+    UnsupportedPaymentMint,
+    #[msg("Payment mint does not match the subscription")]
+    PaymentMintMismatch,
+    #[msg("Conversion rate must be greater than zero")]
+    InvalidConversionRate,
+    #[msg("Transfer fee calculation overflowed")]
+    TransferFeeOverflow,
+    #[msg("Provider payment account is not the provider's associated token account for the payment mint")]
+    InvalidProviderPaymentAccount,
+    #[msg("Owner payment account is not the owner's associated token account for the payment mint")]
+    InvalidOwnerPaymentAccount,
+    #[msg("Subscriber payment account is not the subscriber's associated token account for the payment mint")]
+    InvalidSubscriberPaymentAccount,
+    #[msg("Rating window for this subscription has closed")]
+    RatingWindowClosed,
+    #[msg("This subscription period has already been rated")]
+    AlreadyRated,
+    #[msg("Evidence URI is too long")]
+    EvidenceUriTooLong,
+    #[msg("Evidence account must be provided exactly when evidence is attached")]
+    EvidenceAccountMismatch,
+    #[msg("Rating not found or no longer disputable")]
+    RatingNotFound,
+    #[msg("Not the dispute arbiter")]
+    NotArbiter,
+    #[msg("Invalid slashing parameters")]
+    InvalidSlashingParams,
+    #[msg("Insufficient stake")]
+    InsufficientStake,
+    #[msg("Provider still has active subscriptions")]
+    ActiveSubscriptionsExist,
+    #[msg("Stake withdrawal cooldown has not elapsed")]
+    StakeCooldownActive,
+    #[msg("Provider does not meet the slashing conditions")]
+    ProviderNotSlashable,
+    #[msg("Invalid remaining accounts")]
+    InvalidRemainingAccounts,
+    #[msg("No active subscribers to compensate")]
+    NoAffectedSubscribers,
+    #[msg("Too many price cap points")]
+    TooManyPriceCapPoints,
+    #[msg("Invalid price cap curve")]
+    InvalidPriceCapCurve,
+    #[msg("Price per day is above the cap for this provider's reputation")]
+    PriceAboveReputationCap,
+    #[msg("Invalid QualityInfo account")]
+    InvalidQualityInfo,
+    #[msg("QualityInfo must be migrated to the current version")]
+    QualityInfoNotMigrated,
+    #[msg("QualityInfo is already on the current version")]
+    QualityInfoAlreadyMigrated,
+    #[msg("Invalid delivery target")]
+    InvalidDeliveryTarget,
+    #[msg("Provider has not published an encryption key")]
+    ProviderEncryptionKeyMissing,
+    #[msg("Recipient was updated too recently")]
+    RecipientUpdateTooSoon,
+    #[msg("Recipient not found")]
+    RecipientNotFound,
+    #[msg("Invalid number of recipients")]
+    InvalidRecipientCount,
+    #[msg("Recipient label is too long")]
+    RecipientLabelTooLong,
+    #[msg("Beneficiary must be the subscriber; use gift_subscription to subscribe for someone else")]
+    InvalidBeneficiary,
+    #[msg("Signer does not hold this subscription")]
+    NotSubscriptionHolder,
+    #[msg("Pass mint, pass token account and pass token program must be provided together")]
+    PassAccountsMismatch,
+    #[msg("Seat count must be greater than zero")]
+    InvalidSeatCount,
+    #[msg("All seats in this group are taken")]
+    NoSeatsAvailable,
+    #[msg("Fee calculation overflowed")]
+    FeeOverflow,
+    #[msg("Invalid number of providers in bundle")]
+    InvalidBundleSize,
+    #[msg("Invalid bundle discount")]
+    InvalidBundleDiscount,
+    #[msg("Subscriber is banned by this provider")]
+    SubscriberBanned,
+    #[msg("Provider blocklist is full")]
+    BlocklistFull,
+    #[msg("Invalid blocklist account")]
+    InvalidBlocklist,
+    #[msg("Refund account is not the refund recipient's associated token account for the payment mint")]
+    InvalidRefundAccount,
+    #[msg("Provider is at capacity")]
+    ProviderAtCapacity,
+    #[msg("Provider has free slots; subscribe directly")]
+    ProviderHasCapacity,
+    #[msg("Provider waitlist is full")]
+    WaitlistFull,
+    #[msg("Already on the waitlist")]
+    AlreadyWaitlisted,
+    #[msg("Not on the waitlist")]
+    NotWaitlisted,
+    #[msg("Invalid waitlist account")]
+    InvalidWaitlist,
+    #[msg("Invalid State account")]
+    InvalidState,
+    #[msg("State is already on the current layout")]
+    StateAlreadyMigrated,
+    #[msg("Subscription is already on the current layout")]
+    SubscriptionAlreadyMigrated,
+    #[msg("Invalid SubscribersList account")]
+    InvalidSubscribersList,
+    #[msg("SubscribersList is already on the current layout")]
+    SubscribersListAlreadyMigrated,
+    #[msg("Provider config is required for a counted subscription")]
+    ProviderConfigMissing,
+    #[msg("Subscription does not hold a provider slot")]
+    SubscriptionNotCounted,
+    #[msg("Slash compensation must cover every active subscription")]
+    IncompleteSlashCoverage,
+    #[msg("Invalid provider config account")]
+    InvalidProviderConfig,
+    #[msg("Member was added after the rated period ended")]
+    MemberJoinedAfterPeriod,
+    #[msg("Bundles can't deliver to encrypted targets")]
+    EncryptedTargetInBundle,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::{clock::Clock, program_pack::Pack, program_stubs};
+    use spl_token_2022::extension::{
+        transfer_fee::TransferFee, ExtensionType, StateWithExtensionsMut,
+    };
+    use spl_token_2022::state::{Account as TokenAccountState, AccountState, Mint as MintState};
+
+    const EPOCH: u64 = 100;
+
+    // Serves the sysvars the program reads off-chain
+    struct TestSyscalls;
+
+    impl program_stubs::SyscallStubs for TestSyscalls {
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock {
+                epoch: EPOCH,
+                ..Clock::default()
+            };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            0
+        }
+    }
+
+    fn setup() {
+        program_stubs::set_syscall_stubs(Box::new(TestSyscalls));
+    }
+
+    // Raw account contents; borrowed into an AccountInfo with `info`
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+    }
+
+    impl TestAccount {
+        fn new(owner: Pubkey, data: Vec<u8>) -> Self {
+            Self::at(Pubkey::new_unique(), owner, data)
+        }
+
+        fn at(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            TestAccount {
+                key,
+                owner,
+                lamports: 1_000_000_000,
+                data,
+            }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                false,
+                true,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    // Legacy token mints share the Token-2022 base layout
+    fn base_mint() -> MintState {
+        MintState {
+            decimals: 6,
+            is_initialized: true,
+            ..Default::default()
+        }
+    }
+
+    fn legacy_mint() -> TestAccount {
+        let mut data = vec![0u8; MintState::LEN];
+        base_mint().pack_into_slice(&mut data);
+        TestAccount::new(token::ID, data)
+    }
+
+    // Token-2022 mint, with a transfer fee of `fee_bps` capped at
+    // `maximum_fee` when `fee_bps` is set
+    fn token_2022_mint(fee: Option<(u16, u64)>) -> TestAccount {
+        let extensions: &[ExtensionType] = match fee {
+            Some(_) => &[ExtensionType::TransferFeeConfig],
+            None => &[],
+        };
+        let len = ExtensionType::try_calculate_account_len::<MintState>(extensions).unwrap();
+        let mut data = vec![0u8; len];
+        let mut mint = StateWithExtensionsMut::<MintState>::unpack_uninitialized(&mut data).unwrap();
+        if let Some((fee_bps, maximum_fee)) = fee {
+            let fee_config = mint.init_extension::<TransferFeeConfig>(true).unwrap();
+            let transfer_fee = TransferFee {
+                epoch: 0.into(),
+                maximum_fee: maximum_fee.into(),
+                transfer_fee_basis_points: fee_bps.into(),
+            };
+            fee_config.older_transfer_fee = transfer_fee;
+            fee_config.newer_transfer_fee = transfer_fee;
+        }
+        mint.base = base_mint();
+        mint.pack_base();
+        if fee.is_some() {
+            mint.init_account_type().unwrap();
+        }
+        TestAccount::new(spl_token_2022::ID, data)
+    }
+
+    // Amount that arrives when `amount` is sent with the mint's transfer fee
+    fn received(mint: &InterfaceAccount<Mint>, amount: u64) -> u64 {
+        let mint_info = mint.to_account_info();
+        let mint_data = mint_info.try_borrow_data().unwrap();
+        let mint_state = StateWithExtensions::<MintState>::unpack(&mint_data).unwrap();
+        let fee = mint_state
+            .get_extension::<TransferFeeConfig>()
+            .map_or(0, |fee_config| fee_config.calculate_epoch_fee(EPOCH, amount).unwrap());
+        amount - fee
+    }
+
+    fn subscription(start_time: i64, end_time: i64) -> Subscription {
+        Subscription {
+            version: SUBSCRIPTION_VERSION,
+            start_time,
+            end_time,
+            recipients: Vec::new(),
+            payment_mint: Pubkey::new_unique(),
+            rated_end_time: 0,
+            last_recipient_update: 0,
+            subscriber: Pubkey::new_unique(),
+            payer: Pubkey::new_unique(),
+            refund_to_payer: false,
+            pass_mint: None,
+            paid_amount: 0,
+            paid_since: start_time,
+            counted: false,
+        }
+    }
+
+    fn ratings(value: u8) -> QualityRatings {
+        QualityRatings {
+            accuracy: value,
+            latency: value,
+            uptime: value,
+            completeness: value,
+        }
+    }
+
+    fn quality_info() -> QualityInfo {
+        let mut quality_info = QualityInfo::default();
+        quality_info.initialize();
+        quality_info
+    }
+
+    #[test]
+    fn score_is_neutral_without_ratings() {
+        assert_eq!(quality_info().score_bps(), NEUTRAL_REPUTATION_SCORE_BPS);
+    }
+
+    #[test]
+    fn score_weights_ratings_by_period() {
+        setup();
+        let mut quality_info = quality_info();
+        let rater = Pubkey::new_unique();
+        quality_info.record_rating(rater, ratings(90), &subscription(0, 3 * MIN_SUBSCRIPTION_PERIOD)).unwrap();
+        quality_info.record_rating(rater, ratings(10), &subscription(0, MIN_SUBSCRIPTION_PERIOD)).unwrap();
+
+        assert_eq!(quality_info.score_bps(), 7_000);
+    }
+
+    #[test]
+    fn struck_rating_no_longer_counts_towards_score() {
+        setup();
+        let mut quality_info = quality_info();
+        let rater = Pubkey::new_unique();
+        quality_info.record_rating(rater, ratings(80), &subscription(0, MIN_SUBSCRIPTION_PERIOD)).unwrap();
+        quality_info.record_rating(rater, ratings(0), &subscription(0, MIN_SUBSCRIPTION_PERIOD)).unwrap();
+        assert_eq!(quality_info.score_bps(), 4_000);
+
+        let record = quality_info.qualities[1].clone();
+        quality_info.strike_rating(1, &record);
+        assert_eq!(quality_info.score_bps(), 8_000);
+
+        let record = quality_info.qualities[0].clone();
+        quality_info.strike_rating(0, &record);
+        assert_eq!(quality_info.score_bps(), NEUTRAL_REPUTATION_SCORE_BPS);
+    }
+
+    // A QualityInfo account as written by the original program
+    fn baseline_quality_info(current_index: u8, qualities: &[(Pubkey, u8)]) -> TestAccount {
+        let mut records = vec![
+            QualityRecordV1 { provider: Pubkey::default(), quality: 0 };
+            MAX_QUALITY_RECORDS
+        ];
+        for (record, (provider, quality)) in records.iter_mut().zip(qualities) {
+            *record = QualityRecordV1 { provider: *provider, quality: *quality };
+        }
+        let mut data = QualityInfo::DISCRIMINATOR.to_vec();
+        QualityInfoV1 {
+            subscriber: Pubkey::default(),
+            quality: 0,
+            current_index,
+            qualities: records,
+        }
+        .serialize(&mut data)
+        .unwrap();
+        assert_eq!(data.len(), QualityInfoV1::SIZE);
+        TestAccount::new(crate::ID, data)
+    }
+
+    #[test]
+    fn migrates_full_baseline_quality_info_oldest_first() {
+        let raters: Vec<Pubkey> = (0..MAX_QUALITY_RECORDS).map(|_| Pubkey::new_unique()).collect();
+        let qualities: Vec<(Pubkey, u8)> = raters
+            .iter()
+            .enumerate()
+            .map(|(i, rater)| (*rater, i as u8 * 10))
+            .collect();
+        let mut account = baseline_quality_info(3, &qualities);
+
+        let quality_info = QualityInfo::from(QualityInfoV1::load(&account.info()).unwrap());
+
+        assert_eq!(quality_info.version, QUALITY_INFO_VERSION);
+        assert_eq!(quality_info.rating_count, MAX_QUALITY_RECORDS as u64);
+        assert_eq!(quality_info.current_index, 0);
+        assert_eq!(quality_info.total_weight, MAX_QUALITY_RECORDS as u64 * MIN_SUBSCRIPTION_PERIOD as u64);
+        // The oldest rating was the one current_index pointed at
+        assert_eq!(quality_info.qualities[0].rater, raters[3]);
+        assert_eq!(quality_info.qualities[0].ratings.uptime, 30);
+        assert_eq!(quality_info.qualities[9].rater, raters[2]);
+        assert_eq!(quality_info.score_bps(), 4_500);
+    }
+
+    #[test]
+    fn migrates_partial_baseline_quality_info() {
+        let rater = Pubkey::new_unique();
+        let mut account = baseline_quality_info(2, &[(rater, 40), (rater, 60)]);
+
+        let quality_info = QualityInfo::from(QualityInfoV1::load(&account.info()).unwrap());
+
+        assert_eq!(quality_info.rating_count, 2);
+        assert_eq!(quality_info.current_index, 2);
+        assert_eq!(quality_info.qualities.len(), MAX_QUALITY_RECORDS);
+        assert_eq!(quality_info.qualities[1].ratings.accuracy, 60);
+        assert_eq!(quality_info.score_bps(), 5_000);
+        // Migrated ratings carry no score, so they can't count towards slashing
+        assert!(!quality_info.stayed_below(10_000, 1));
+    }
+
+    #[test]
+    fn subscription_space_fits_its_recipients() {
+        let mut subscription = subscription(0, MIN_SUBSCRIPTION_PERIOD);
+        subscription.pass_mint = Some(Pubkey::new_unique());
+        subscription.recipients = vec![
+            Recipient { label: None, target: DeliveryTarget::Pubkey(Pubkey::new_unique()) },
+            Recipient {
+                label: Some("alerts".to_string()),
+                target: DeliveryTarget::Webhook("https://example.com/hook".to_string()),
+            },
+            Recipient { label: None, target: DeliveryTarget::Encrypted(vec![1; 120]) },
+        ];
+
+        let mut data = Vec::new();
+        subscription.try_serialize(&mut data).unwrap();
+
+        assert_eq!(Subscription::space(&subscription.recipients), data.len());
+        assert!(Subscription::space(&subscription.recipients) < 8 + Subscription::BASE_SIZE + MAX_RECIPIENTS * Recipient::SIZE);
+    }
+
+    #[test]
+    fn events_carry_only_non_plaintext_targets() {
+        let pubkey = DeliveryTarget::Pubkey(Pubkey::new_unique());
+        let encrypted = DeliveryTarget::Encrypted(vec![7; 64]);
+
+        assert!(matches!(pubkey.emitted(), Some(DeliveryTarget::Pubkey(_))));
+        assert!(matches!(encrypted.emitted(), Some(DeliveryTarget::Encrypted(_))));
+        assert!(DeliveryTarget::Webhook("https://example.com/hook".to_string()).emitted().is_none());
+        assert!(DeliveryTarget::AgentId("agent-1".to_string()).emitted().is_none());
+    }
+
+    #[test]
+    fn target_hash_is_salted_with_the_subscription() {
+        let target = DeliveryTarget::Webhook("https://example.com/hook".to_string());
+        let subscription = Pubkey::new_unique();
+
+        assert_eq!(target.hash(&subscription), target.hash(&subscription));
+        assert_ne!(target.hash(&subscription), target.hash(&Pubkey::new_unique()));
+        assert_ne!(
+            target.hash(&subscription),
+            anchor_lang::solana_program::hash::hash(&target.try_to_vec().unwrap()).to_bytes()
+        );
+    }
+
+    #[test]
+    fn migrates_baseline_subscribers_list() {
+        let subscribers = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut data = SubscribersList::DISCRIMINATOR.to_vec();
+        SubscribersListV1 { subscribers: subscribers.clone() }.serialize(&mut data).unwrap();
+        data.resize(SubscribersListV1::SIZE, 0);
+        let mut account = TestAccount::new(crate::ID, data);
+
+        let info = account.info();
+        let subscribers_list = load_subscribers_list(&info).unwrap();
+
+        assert_eq!(subscribers_list.subscribers.len(), 2);
+        for (entry, subscriber) in subscribers_list.subscribers.iter().zip(&subscribers) {
+            assert_eq!(entry.subscriber, *subscriber);
+            assert_eq!(entry.seats, 1);
+        }
+    }
+
+    #[test]
+    fn group_period_rating_is_weighted_by_the_group_period() {
+        setup();
+        let mut quality_info = quality_info();
+        quality_info
+            .record_period_rating(Pubkey::new_unique(), ratings(60), 100, 100 + 2 * MIN_SUBSCRIPTION_PERIOD)
+            .unwrap();
+
+        let record = &quality_info.qualities[0];
+        assert_eq!(record.weight, 2 * MIN_SUBSCRIPTION_PERIOD as u64);
+        assert_eq!((record.period_start, record.period_end), (100, 100 + 2 * MIN_SUBSCRIPTION_PERIOD));
+        assert_eq!(quality_info.score_bps(), 6_000);
+    }
+
+    #[test]
+    fn price_cap_follows_the_highest_breakpoint_reached() {
+        let mut state = state();
+        assert_eq!(state.price_cap(0), None);
+
+        let points = vec![
+            PriceCapPoint { min_score_bps: 0, max_fee_per_day: 10 },
+            PriceCapPoint { min_score_bps: 2_000, max_fee_per_day: 50 },
+            PriceCapPoint { min_score_bps: 8_000, max_fee_per_day: 200 },
+        ];
+        validate_price_caps(&points).unwrap();
+        state.price_caps = points;
+
+        assert_eq!(state.price_cap(0), Some(10));
+        assert_eq!(state.price_cap(1_999), Some(10));
+        assert_eq!(state.price_cap(2_000), Some(50));
+        assert_eq!(state.price_cap(7_999), Some(50));
+        assert_eq!(state.price_cap(10_000), Some(200));
+    }
+
+    #[test]
+    fn price_cap_curve_must_start_at_zero_and_increase() {
+        let point = |min_score_bps| PriceCapPoint { min_score_bps, max_fee_per_day: 100 };
+        let invalid = |points: &[PriceCapPoint]| validate_price_caps(points).err();
+
+        assert!(validate_price_caps(&[]).is_ok());
+        assert!(validate_price_caps(&[point(0), point(5_000)]).is_ok());
+        // Missing leading breakpoint
+        assert_eq!(invalid(&[point(1_000)]), Some(SubscriptionError::InvalidPriceCapCurve.into()));
+        // Unsorted or repeated breakpoints
+        assert_eq!(
+            invalid(&[point(0), point(5_000), point(3_000)]),
+            Some(SubscriptionError::InvalidPriceCapCurve.into())
+        );
+        assert_eq!(
+            invalid(&[point(0), point(5_000), point(5_000)]),
+            Some(SubscriptionError::InvalidPriceCapCurve.into())
+        );
+        // Too many points
+        let points: Vec<PriceCapPoint> = (0..=MAX_PRICE_CAP_POINTS as u64).map(|i| point(i * 100)).collect();
+        assert_eq!(invalid(&points), Some(SubscriptionError::TooManyPriceCapPoints.into()));
+        assert!(validate_price_caps(&points[..MAX_PRICE_CAP_POINTS]).is_ok());
+    }
+
+    #[test]
+    fn stayed_below_requires_every_rating_in_the_window() {
+        setup();
+        let mut quality_info = quality_info();
+        let rater = Pubkey::new_unique();
+        for value in [80, 0, 0, 0] {
+            quality_info.record_rating(rater, ratings(value), &subscription(0, MIN_SUBSCRIPTION_PERIOD)).unwrap();
+        }
+        // Scores after each rating: 8000, 4000, 2666, 2000
+        assert!(quality_info.stayed_below(5_000, 3));
+        assert!(!quality_info.stayed_below(5_000, 4));
+        assert!(!quality_info.stayed_below(3_000, 3));
+        assert!(!quality_info.stayed_below(5_000, 0));
+    }
+
+    #[test]
+    fn struck_rating_breaks_the_slashing_window() {
+        setup();
+        let mut quality_info = quality_info();
+        let rater = Pubkey::new_unique();
+        for _ in 0..3 {
+            quality_info.record_rating(rater, ratings(0), &subscription(0, MIN_SUBSCRIPTION_PERIOD)).unwrap();
+        }
+        assert!(quality_info.stayed_below(5_000, 3));
+
+        let record = quality_info.qualities[1].clone();
+        quality_info.strike_rating(1, &record);
+        assert!(!quality_info.stayed_below(5_000, 3));
+        assert!(quality_info.stayed_below(5_000, 1));
+    }
+
+    const TOKEN_PROGRAMS: [Pubkey; 2] = [token::ID, spl_token_2022::ID];
+
+    // `wallet`'s associated token account for `mint` under `token_program`
+    fn associated_token_account(wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> TestAccount {
+        let key = get_associated_token_address_with_program_id(wallet, mint, token_program);
+        token_account_at(key, wallet, mint, token_program)
+    }
+
+    fn token_account_at(key: Pubkey, wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> TestAccount {
+        let mut data = vec![0u8; TokenAccountState::LEN];
+        TokenAccountState {
+            mint: *mint,
+            owner: *wallet,
+            state: AccountState::Initialized,
+            ..Default::default()
+        }
+        .pack_into_slice(&mut data);
+        TestAccount::at(key, *token_program, data)
+    }
+
+    fn pass_token_account(holder: &Pubkey, pass_mint: &Pubkey, amount: u64) -> TokenAccount {
+        let mut data = vec![0u8; TokenAccountState::LEN];
+        TokenAccountState {
+            mint: *pass_mint,
+            owner: *holder,
+            amount,
+            state: AccountState::Initialized,
+            ..Default::default()
+        }
+        .pack_into_slice(&mut data);
+        TokenAccount::try_deserialize_unchecked(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn pass_subscription_refunds_the_pass_holder() {
+        let mut subscription = subscription(0, MIN_SUBSCRIPTION_PERIOD);
+        let pass_mint = Pubkey::new_unique();
+        let holder = Pubkey::new_unique();
+        subscription.pass_mint = Some(pass_mint);
+
+        let held = pass_token_account(&holder, &pass_mint, 1);
+        assert_eq!(subscription.refund_recipient_for(Some(&held)), Some(holder));
+        // Without the pass there is nobody to refund
+        assert_eq!(subscription.refund_recipient_for(None), None);
+        let empty = pass_token_account(&holder, &pass_mint, 0);
+        assert_eq!(subscription.refund_recipient_for(Some(&empty)), None);
+        let other_mint = pass_token_account(&holder, &Pubkey::new_unique(), 1);
+        assert_eq!(subscription.refund_recipient_for(Some(&other_mint)), None);
+
+        // A gift refunded to its payer still goes to the payer
+        subscription.refund_to_payer = true;
+        assert_eq!(subscription.refund_recipient_for(None), Some(subscription.payer));
+    }
+
+    #[test]
+    fn subscription_without_pass_refunds_the_subscriber() {
+        let subscription = subscription(0, MIN_SUBSCRIPTION_PERIOD);
+        assert_eq!(subscription.refund_recipient_for(None), Some(subscription.subscriber));
+    }
+
+    fn is_ata(account: &mut TestAccount, wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> bool {
+        let info = account.info();
+        let account = InterfaceAccount::<token_interface::TokenAccount>::try_from(&info).unwrap();
+        is_associated_token_account(&account, wallet, mint, token_program)
+    }
+
+    // Wallets behind the payment account constraints
+    struct Parties {
+        provider: Pubkey,
+        subscriber: Pubkey,
+        owner: Pubkey,
+        mint: Pubkey,
+    }
+
+    fn parties() -> Parties {
+        Parties {
+            provider: Pubkey::new_unique(),
+            subscriber: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+        }
+    }
+
+    #[test]
+    fn payment_atas_accepted_for_both_token_programs() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            for wallet in [parties.provider, parties.subscriber, parties.owner] {
+                let mut ata = associated_token_account(&wallet, &parties.mint, &token_program);
+                assert!(is_ata(&mut ata, &wallet, &parties.mint, &token_program));
+            }
+        }
+    }
+
+    #[test]
+    fn provider_payment_ata_rejects_subscriber_ata() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            let mut ata = associated_token_account(&parties.subscriber, &parties.mint, &token_program);
+            assert!(!is_ata(&mut ata, &parties.provider, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn subscriber_payment_ata_rejects_owner_ata() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            let mut ata = associated_token_account(&parties.owner, &parties.mint, &token_program);
+            assert!(!is_ata(&mut ata, &parties.subscriber, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn owner_payment_ata_rejects_provider_ata() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            let mut ata = associated_token_account(&parties.provider, &parties.mint, &token_program);
+            assert!(!is_ata(&mut ata, &parties.owner, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn refund_ata_rejects_provider_ata() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            let mut ata = associated_token_account(&parties.provider, &parties.mint, &token_program);
+            assert!(!is_ata(&mut ata, &parties.subscriber, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn payment_ata_rejects_other_mint() {
+        let parties = parties();
+        let other_mint = Pubkey::new_unique();
+        for token_program in TOKEN_PROGRAMS {
+            let mut ata = associated_token_account(&parties.subscriber, &other_mint, &token_program);
+            assert!(!is_ata(&mut ata, &parties.subscriber, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn payment_ata_rejects_other_token_program() {
+        let parties = parties();
+        let [legacy, token_2022] = TOKEN_PROGRAMS;
+        for (ata_program, expected_program) in [(legacy, token_2022), (token_2022, legacy)] {
+            let mut ata = associated_token_account(&parties.subscriber, &parties.mint, &ata_program);
+            assert!(!is_ata(&mut ata, &parties.subscriber, &parties.mint, &expected_program));
+        }
+    }
+
+    #[test]
+    fn payment_ata_rejects_non_associated_account_of_wallet() {
+        let parties = parties();
+        for token_program in TOKEN_PROGRAMS {
+            let mut account = token_account_at(
+                Pubkey::new_unique(),
+                &parties.subscriber,
+                &parties.mint,
+                &token_program,
+            );
+            assert!(!is_ata(&mut account, &parties.subscriber, &parties.mint, &token_program));
+        }
+    }
+
+    #[test]
+    fn gross_up_leaves_legacy_token_amounts_unchanged() {
+        setup();
+        let mut mint = legacy_mint();
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+
+        assert_eq!(gross_up_for_transfer_fee(&mint, 1_000_000).unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn gross_up_leaves_token_2022_amounts_without_fee_unchanged() {
+        setup();
+        let mut mint = token_2022_mint(None);
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+
+        assert_eq!(gross_up_for_transfer_fee(&mint, 1_000_000).unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn gross_up_covers_token_2022_transfer_fee() {
+        setup();
+        let mut mint = token_2022_mint(Some((250, u64::MAX)));
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+
+        for net_amount in [1, 999, 1_000_000, 123_456_789] {
+            let gross_amount = gross_up_for_transfer_fee(&mint, net_amount).unwrap();
+            assert!(gross_amount > net_amount);
+            assert_eq!(received(&mint, gross_amount), net_amount);
+        }
+    }
+
+    #[test]
+    fn gross_up_respects_token_2022_maximum_fee() {
+        setup();
+        let mut mint = token_2022_mint(Some((250, 5_000)));
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+
+        let gross_amount = gross_up_for_transfer_fee(&mint, 10_000_000).unwrap();
+        assert_eq!(gross_amount, 10_005_000);
+        assert_eq!(received(&mint, gross_amount), 10_000_000);
+    }
+
+    #[test]
+    fn gross_up_reports_overflow() {
+        setup();
+        let mut mint = token_2022_mint(Some((250, u64::MAX)));
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+
+        assert!(gross_up_for_transfer_fee(&mint, u64::MAX).is_err());
+    }
+
+    fn waitlist_entry(joined_at: i64) -> WaitlistEntry {
+        WaitlistEntry {
+            subscriber: Pubkey::new_unique(),
+            joined_at,
+            deposit: WAITLIST_DEPOSIT,
+        }
+    }
+
+    #[test]
+    fn expired_waitlist_entries_do_not_hold_the_queue() {
+        let queue = vec![waitlist_entry(0), waitlist_entry(100), waitlist_entry(200)];
+        let now = WAITLIST_ENTRY_TTL + 150;
+
+        assert_eq!(waitlist_position(&queue, &queue[0].subscriber, 50), 0);
+        assert_eq!(waitlist_position(&queue, &queue[2].subscriber, 50), 2);
+        assert_eq!(waitlist_position(&queue, &queue[2].subscriber, now), 0);
+        assert_eq!(waitlist_position(&queue, &Pubkey::new_unique(), now), 1);
+    }
+
+    #[test]
+    fn eviction_returns_expired_waitlist_entries() {
+        let mut queue = vec![waitlist_entry(0), waitlist_entry(100), waitlist_entry(200)];
+        let live = queue[2].subscriber;
+
+        let expired = evict_expired_entries(&mut queue, WAITLIST_ENTRY_TTL + 100);
+        assert_eq!(expired.len(), 2);
+        assert_eq!(expired.iter().map(|entry| entry.deposit).sum::<u64>(), 2 * WAITLIST_DEPOSIT);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].subscriber, live);
+    }
+
+    fn coupon(discount: CouponDiscount, max_redemptions: u32, whitelist: Vec<Pubkey>) -> Coupon {
+        Coupon {
+            data_provider: Pubkey::new_unique(),
+            coupon_id: 1,
+            discount,
+            expires_at: 1_000,
+            max_redemptions,
+            redemptions: 0,
+            whitelist,
+        }
+    }
+
+    #[test]
+    fn coupon_cannot_be_redeemed_once_expired() {
+        let mut coupon = coupon(CouponDiscount::Percent(10), 5, Vec::new());
+        let subscriber = Pubkey::new_unique();
+
+        assert_eq!(coupon.redeem(&subscriber, 999, 1_000).unwrap(), 900);
+        assert_eq!(
+            coupon.redeem(&subscriber, 1_000, 1_000).unwrap_err(),
+            SubscriptionError::CouponExpired.into()
+        );
+        assert_eq!(coupon.redemptions, 1);
+    }
+
+    #[test]
+    fn coupon_stops_at_max_redemptions() {
+        let mut coupon = coupon(CouponDiscount::Fixed(100), 2, Vec::new());
+
+        for _ in 0..2 {
+            assert_eq!(coupon.redeem(&Pubkey::new_unique(), 0, 1_000).unwrap(), 900);
+        }
+        assert_eq!(
+            coupon.redeem(&Pubkey::new_unique(), 0, 1_000).unwrap_err(),
+            SubscriptionError::CouponExhausted.into()
+        );
+        assert_eq!(coupon.redemptions, 2);
+    }
+
+    #[test]
+    fn whitelisted_subscriber_reuses_coupon_until_exhausted() {
+        let subscriber = Pubkey::new_unique();
+        let mut coupon = coupon(CouponDiscount::Percent(50), 2, vec![subscriber]);
+
+        assert_eq!(
+            coupon.redeem(&Pubkey::new_unique(), 0, 1_000).unwrap_err(),
+            SubscriptionError::CouponNotWhitelisted.into()
+        );
+        // Each reuse counts against the coupon's redemptions
+        assert_eq!(coupon.redeem(&subscriber, 0, 1_000).unwrap(), 500);
+        assert_eq!(coupon.redeem(&subscriber, 0, 1_000).unwrap(), 500);
+        assert_eq!(
+            coupon.redeem(&subscriber, 0, 1_000).unwrap_err(),
+            SubscriptionError::CouponExhausted.into()
+        );
+    }
+
+    #[test]
+    fn coupon_discount_rounds_in_the_providers_favour() {
+        let redeem = |discount, fee| {
+            coupon(discount, 1, Vec::new()).redeem(&Pubkey::new_unique(), 0, fee).unwrap()
+        };
+
+        // 33% of 10 is 3.3; the discount rounds down to 3
+        assert_eq!(redeem(CouponDiscount::Percent(33), 10), 7);
+        assert_eq!(redeem(CouponDiscount::Percent(10), 9), 9);
+        assert_eq!(redeem(CouponDiscount::Percent(100), 10), 0);
+        assert_eq!(redeem(CouponDiscount::Percent(0), 10), 10);
+        // A fixed discount larger than the fee makes it free rather than failing
+        assert_eq!(redeem(CouponDiscount::Fixed(25), 10), 0);
+    }
+
+    fn state() -> State {
+        State::from(StateV1 {
+            owner: Pubkey::new_unique(),
+            nft_program_id: Pubkey::new_unique(),
+            payment_spl_token: Pubkey::new_unique(),
+            fee_per_day: 100,
+            collector_fee: 10,
+        })
+    }
+
+    #[test]
+    fn volume_discount_applies_from_each_tier_boundary() {
+        let mut state = state();
+        state.volume_discounts = vec![
+            VolumeDiscount { min_duration: 30 * MIN_SUBSCRIPTION_PERIOD, discount_bps: 1_000 },
+            VolumeDiscount { min_duration: 365 * MIN_SUBSCRIPTION_PERIOD, discount_bps: 2_500 },
+        ];
+
+        assert_eq!(calculate_provider_fee(&state, 100, 30 * MIN_SUBSCRIPTION_PERIOD - 1), (2_999, 0));
+        assert_eq!(calculate_provider_fee(&state, 100, 30 * MIN_SUBSCRIPTION_PERIOD), (2_700, 1_000));
+        assert_eq!(calculate_provider_fee(&state, 100, 364 * MIN_SUBSCRIPTION_PERIOD), (32_760, 1_000));
+        assert_eq!(calculate_provider_fee(&state, 100, 365 * MIN_SUBSCRIPTION_PERIOD), (27_375, 2_500));
+    }
+
+    #[test]
+    fn provider_fee_rounds_down_for_small_daily_fees() {
+        let mut state = state();
+        assert_eq!(calculate_provider_fee(&state, 1, MIN_SUBSCRIPTION_PERIOD), (1, 0));
+        // Partial days are not charged
+        assert_eq!(calculate_provider_fee(&state, 1, 2 * MIN_SUBSCRIPTION_PERIOD - 1), (1, 0));
+
+        // The discount rounds down too, so a tiny fee keeps its last unit
+        state.volume_discounts = vec![VolumeDiscount { min_duration: 0, discount_bps: 2_500 }];
+        assert_eq!(calculate_provider_fee(&state, 1, 3 * MIN_SUBSCRIPTION_PERIOD), (3, 2_500));
+        assert_eq!(calculate_provider_fee(&state, 1, 4 * MIN_SUBSCRIPTION_PERIOD), (3, 2_500));
+    }
+
+    #[test]
+    fn rating_weight_is_capped_at_the_full_weight_period() {
+        setup();
+        let mut quality_info = quality_info();
+        let rater = Pubkey::new_unique();
+        quality_info.record_rating(rater, ratings(90), &subscription(0, 3 * FULL_WEIGHT_PERIOD / 2)).unwrap();
+        quality_info.record_rating(rater, ratings(90), &subscription(0, FULL_WEIGHT_PERIOD)).unwrap();
+        // A decade-long subscription counts no more than a month-long one
+        quality_info.record_rating(rater, ratings(0), &subscription(0, 3_650 * MIN_SUBSCRIPTION_PERIOD)).unwrap();
+
+        assert_eq!(quality_info.qualities[2].weight, FULL_WEIGHT_PERIOD as u64);
+        assert_eq!(quality_info.total_weight, 3 * FULL_WEIGHT_PERIOD as u64);
+        assert_eq!(quality_info.score_bps(), 6_000);
+    }
+
+    #[test]
+    fn uncapped_weights_saturate_instead_of_overflowing() {
+        let mut quality_info = quality_info();
+        quality_info.fold_rating(ratings(100), u64::MAX / 2);
+        quality_info.fold_rating(ratings(100), u64::MAX / 2);
+
+        assert_eq!(quality_info.accuracy.weighted_sum_sq, u64::MAX);
+        assert_eq!(quality_info.total_weight, u64::MAX - 1);
+    }
+
+    #[test]
+    fn registered_provider_config_is_always_read() {
+        let mut unregistered = TestAccount::new(Pubkey::default(), Vec::new());
+        assert!(load_provider_config(&unregistered.info()).unwrap().is_none());
+
+        let mut data = Vec::new();
+        ProviderConfig {
+            data_provider: Pubkey::new_unique(),
+            active_subscriptions: 2,
+            stake: 0,
+            withdrawal_requested_at: 0,
+            ratings_at_last_slash: 0,
+            fee_per_day: 500,
+            encryption_key: [0; 32],
+            max_recipients: 0,
+            max_subscribers: 3,
+        }
+        .try_serialize(&mut data)
+        .unwrap();
+        data.resize(8 + ProviderConfig::SIZE, 0);
+        let mut registered = TestAccount::new(crate::ID, data);
+        let info = registered.info();
+
+        let mut provider_config = load_provider_config(&info).unwrap();
+        let settings = ProviderConfig::settings(provider_config.as_ref());
+        assert_eq!(settings.fee_per_day, 500);
+        assert_eq!(settings.free_slots(), Some(1));
+
+        provider_config.as_mut().unwrap().active_subscriptions += 1;
+        store_provider_config(&info, &provider_config).unwrap();
+        assert_eq!(load_provider_config(&info).unwrap().unwrap().free_slots(), Some(0));
+
+        let mut foreign = TestAccount::new(Pubkey::new_unique(), vec![1; 8 + ProviderConfig::SIZE]);
+        assert_eq!(
+            load_provider_config(&foreign.info()).err(),
+            Some(SubscriptionError::InvalidProviderConfig.into())
+        );
+    }
+
+    #[test]
+    fn delivery_targets_are_validated_per_variant() {
+        // Webhook URL of `len` bytes
+        let webhook = |len: usize| {
+            DeliveryTarget::Webhook(format!("https://{}", "a".repeat(len - "https://".len())))
+        };
+        let invalid = |target: DeliveryTarget| target.validate().err();
+
+        assert!(DeliveryTarget::Pubkey(Pubkey::new_unique()).validate().is_ok());
+
+        assert!(webhook(MAX_WEBHOOK_URL_LEN).validate().is_ok());
+        assert!(webhook("https://".len() + 1).validate().is_ok());
+        assert_eq!(
+            invalid(webhook(MAX_WEBHOOK_URL_LEN + 1)),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+        assert_eq!(
+            invalid(DeliveryTarget::Webhook("https://".to_string())),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+        assert_eq!(
+            invalid(DeliveryTarget::Webhook("http://example.com/hook".to_string())),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+
+        assert!(DeliveryTarget::AgentId("a".repeat(MAX_AGENT_ID_LEN)).validate().is_ok());
+        assert_eq!(
+            invalid(DeliveryTarget::AgentId("a".repeat(MAX_AGENT_ID_LEN + 1))),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+        assert_eq!(
+            invalid(DeliveryTarget::AgentId(String::new())),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+
+        assert!(DeliveryTarget::Encrypted(vec![1; MAX_ENCRYPTED_TARGET_LEN]).validate().is_ok());
+        assert_eq!(
+            invalid(DeliveryTarget::Encrypted(vec![1; MAX_ENCRYPTED_TARGET_LEN + 1])),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+        assert_eq!(
+            invalid(DeliveryTarget::Encrypted(Vec::new())),
+            Some(SubscriptionError::InvalidDeliveryTarget.into())
+        );
+    }
+
+    #[test]
+    fn recipient_label_length_is_limited() {
+        let recipient = |label: String| Recipient {
+            label: Some(label),
+            target: DeliveryTarget::Pubkey(Pubkey::new_unique()),
+        };
+
+        assert!(recipient("a".repeat(MAX_RECIPIENT_LABEL_LEN)).validate().is_ok());
+        assert_eq!(
+            recipient("a".repeat(MAX_RECIPIENT_LABEL_LEN + 1)).validate().err(),
+            Some(SubscriptionError::RecipientLabelTooLong.into())
+        );
+        // The target is checked too
+        let recipient = Recipient { label: None, target: DeliveryTarget::AgentId(String::new()) };
+        assert_eq!(recipient.validate().err(), Some(SubscriptionError::InvalidDeliveryTarget.into()));
+    }
+
+    #[test]
+    fn recipient_updates_wait_out_the_cooldown() {
+        let mut subscription = subscription(0, MIN_SUBSCRIPTION_PERIOD);
+        subscription.last_recipient_update = 1_000;
+
+        assert_eq!(
+            subscription.check_recipient_update(1_000 + RECIPIENT_UPDATE_COOLDOWN - 1).err(),
+            Some(SubscriptionError::RecipientUpdateTooSoon.into())
+        );
+        assert!(subscription.check_recipient_update(1_000 + RECIPIENT_UPDATE_COOLDOWN).is_ok());
+        // Ended subscriptions can't be updated however long ago the last update was
+        assert_eq!(
+            subscription.check_recipient_update(MIN_SUBSCRIPTION_PERIOD).err(),
+            Some(SubscriptionError::SubscriptionAlreadyEnded.into())
+        );
+    }
+
+    fn group(start_time: i64, end_time: i64) -> GroupSubscription {
+        GroupSubscription {
+            admin: Pubkey::new_unique(),
+            data_provider: Pubkey::new_unique(),
+            group_id: 0,
+            seats: 2,
+            members: 2,
+            start_time,
+            end_time,
+            payment_mint: Pubkey::new_unique(),
+            counted: true,
+            period_ratings: 0,
+        }
+    }
+
+    fn membership(added_at: i64) -> GroupMembership {
+        GroupMembership {
+            group: Pubkey::new_unique(),
+            member: Pubkey::new_unique(),
+            added_at,
+            recipient: Recipient { label: None, target: DeliveryTarget::Pubkey(Pubkey::new_unique()) },
+            rated_end_time: 0,
+        }
+    }
+
+    #[test]
+    fn group_members_rate_within_the_rating_window() {
+        let end_time = MIN_SUBSCRIPTION_PERIOD;
+        let group = group(0, end_time);
+        let member = membership(0);
+
+        assert!(group.check_member_rating(&member, end_time / 2).is_ok());
+        assert!(group.check_member_rating(&member, end_time + RATING_WINDOW - 1).is_ok());
+        assert_eq!(
+            group.check_member_rating(&member, end_time + RATING_WINDOW).err(),
+            Some(SubscriptionError::RatingWindowClosed.into())
+        );
+    }
+
+    #[test]
+    fn members_added_after_the_period_cannot_rate_it() {
+        let end_time = MIN_SUBSCRIPTION_PERIOD;
+        let group = group(0, end_time);
+
+        assert!(group.check_member_rating(&membership(end_time - 1), end_time).is_ok());
+        assert_eq!(
+            group.check_member_rating(&membership(end_time), end_time + 1).err(),
+            Some(SubscriptionError::MemberJoinedAfterPeriod.into())
+        );
+    }
+
+    #[test]
+    fn group_period_takes_one_rating_per_member_and_seat() {
+        let mut group = group(0, MIN_SUBSCRIPTION_PERIOD);
+        let mut member = membership(0);
+        member.rated_end_time = group.end_time;
+        assert_eq!(
+            group.check_member_rating(&member, 1).err(),
+            Some(SubscriptionError::AlreadyRated.into())
+        );
+
+        group.period_ratings = group.seats;
+        assert_eq!(
+            group.check_member_rating(&membership(0), 1).err(),
+            Some(SubscriptionError::AlreadyRated.into())
+        );
+    }
+
+    #[test]
+    fn unused_amount_covers_the_time_left() {
+        let mut subscription = subscription(0, 10 * MIN_SUBSCRIPTION_PERIOD);
+        subscription.paid_amount = 1_000;
+
+        assert_eq!(subscription.unused_amount(0), 1_000);
+        assert_eq!(subscription.unused_amount(-MIN_SUBSCRIPTION_PERIOD), 1_000);
+        assert_eq!(subscription.unused_amount(4 * MIN_SUBSCRIPTION_PERIOD), 600);
+        // Partial shares round down
+        assert_eq!(subscription.unused_amount(10 * MIN_SUBSCRIPTION_PERIOD - 1), 0);
+        assert_eq!(subscription.unused_amount(9 * MIN_SUBSCRIPTION_PERIOD + 1), 99);
+        assert_eq!(subscription.unused_amount(10 * MIN_SUBSCRIPTION_PERIOD), 0);
+    }
+
+    #[test]
+    fn unused_amount_only_counts_the_paid_period() {
+        // Renewed at day 4 for 300 more covering days 4 to 10
+        let mut subscription = subscription(0, 10 * MIN_SUBSCRIPTION_PERIOD);
+        subscription.paid_since = 4 * MIN_SUBSCRIPTION_PERIOD;
+        subscription.paid_amount = 300;
+
+        assert_eq!(subscription.unused_amount(2 * MIN_SUBSCRIPTION_PERIOD), 300);
+        assert_eq!(subscription.unused_amount(7 * MIN_SUBSCRIPTION_PERIOD), 150);
+
+        subscription.paid_since = subscription.end_time;
+        assert_eq!(subscription.unused_amount(0), 0);
+    }
+
+    #[test]
+    fn partial_refund_is_grossed_up_for_the_transfer_fee() {
+        setup();
+        let mut subscription = subscription(0, 10 * MIN_SUBSCRIPTION_PERIOD);
+        subscription.paid_amount = 2_000_000;
+        let refund = subscription.unused_amount(5 * MIN_SUBSCRIPTION_PERIOD);
+        assert_eq!(refund, 1_000_000);
+
+        // 1% fee
+        let mut mint = token_2022_mint(Some((100, u64::MAX)));
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+        let gross_amount = gross_up_for_transfer_fee(&mint, refund).unwrap();
+        assert_eq!(gross_amount, 1_010_102);
+        assert_eq!(received(&mint, gross_amount), refund);
+
+        // 1% fee capped at 500
+        let mut mint = token_2022_mint(Some((100, 500)));
+        let info = mint.info();
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+        let gross_amount = gross_up_for_transfer_fee(&mint, refund).unwrap();
+        assert_eq!(gross_amount, 1_000_500);
+        assert_eq!(received(&mint, gross_amount), refund);
+    }
 }
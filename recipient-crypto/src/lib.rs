@@ -0,0 +1,155 @@
+// Client-side helpers for `DeliveryTarget::Encrypted`.
+//
+// A subscriber encrypts the recipient endpoint to the x25519 key the data
+// provider published with `set_provider_encryption_key`. Each blob uses a
+// fresh ephemeral keypair, so only the provider can decrypt it.
+//
+// Blob layout: ephemeral public key (32) || nonce (24) || ciphertext
+
+use crypto_box::{
+    aead::{Aead, AeadCore, OsRng},
+    ChaChaBox, PublicKey, SecretKey, KEY_SIZE,
+};
+
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+// Must match MAX_ENCRYPTED_TARGET_LEN in the program
+pub const MAX_BLOB_LEN: usize = 256;
+pub const MAX_PLAINTEXT_LEN: usize = MAX_BLOB_LEN - KEY_SIZE - NONCE_SIZE - TAG_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecipientCryptoError {
+    PlaintextTooLong,
+    MalformedBlob,
+    DecryptionFailed,
+}
+
+impl std::fmt::Display for RecipientCryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecipientCryptoError::PlaintextTooLong => write!(f, "recipient is too long to encrypt"),
+            RecipientCryptoError::MalformedBlob => write!(f, "encrypted recipient is malformed"),
+            RecipientCryptoError::DecryptionFailed => write!(f, "failed to decrypt recipient"),
+        }
+    }
+}
+
+impl std::error::Error for RecipientCryptoError {}
+
+// Returns (secret key, public key). The public key goes on-chain
+pub fn generate_provider_keypair() -> ([u8; KEY_SIZE], [u8; KEY_SIZE]) {
+    let secret_key = SecretKey::generate(&mut OsRng);
+    let public_key = secret_key.public_key();
+    (secret_key.to_bytes(), public_key.to_bytes())
+}
+
+// Encrypts a serialized recipient to the provider's published key
+pub fn encrypt_recipient(
+    provider_public_key: &[u8; KEY_SIZE],
+    recipient: &[u8],
+) -> Result<Vec<u8>, RecipientCryptoError> {
+    if recipient.len() > MAX_PLAINTEXT_LEN {
+        return Err(RecipientCryptoError::PlaintextTooLong);
+    }
+
+    let ephemeral_secret = SecretKey::generate(&mut OsRng);
+    let provider_public_key = PublicKey::from(*provider_public_key);
+    let cipher = ChaChaBox::new(&provider_public_key, &ephemeral_secret);
+    let nonce = ChaChaBox::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, recipient)
+        .map_err(|_| RecipientCryptoError::PlaintextTooLong)?;
+
+    let mut blob = Vec::with_capacity(KEY_SIZE + NONCE_SIZE + ciphertext.len());
+    blob.extend_from_slice(ephemeral_secret.public_key().as_bytes());
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    Ok(blob)
+}
+
+// Decrypts a blob produced by `encrypt_recipient` with the provider's secret key
+pub fn decrypt_recipient(
+    provider_secret_key: &[u8; KEY_SIZE],
+    blob: &[u8],
+) -> Result<Vec<u8>, RecipientCryptoError> {
+    if blob.len() < KEY_SIZE + NONCE_SIZE + TAG_SIZE || blob.len() > MAX_BLOB_LEN {
+        return Err(RecipientCryptoError::MalformedBlob);
+    }
+
+    let (ephemeral_public_key, rest) = blob.split_at(KEY_SIZE);
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    let ephemeral_public_key = PublicKey::from_slice(ephemeral_public_key)
+        .map_err(|_| RecipientCryptoError::MalformedBlob)?;
+    let provider_secret_key = SecretKey::from(*provider_secret_key);
+    let cipher = ChaChaBox::new(&ephemeral_public_key, &provider_secret_key);
+    cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| RecipientCryptoError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_recipient() {
+        let (secret_key, public_key) = generate_provider_keypair();
+        let recipient = b"https://example.com/hooks/subscription";
+
+        let blob = encrypt_recipient(&public_key, recipient).unwrap();
+
+        assert_eq!(blob.len(), KEY_SIZE + NONCE_SIZE + recipient.len() + TAG_SIZE);
+        assert_eq!(decrypt_recipient(&secret_key, &blob).unwrap(), recipient);
+    }
+
+    #[test]
+    fn round_trips_longest_recipient() {
+        let (secret_key, public_key) = generate_provider_keypair();
+        let recipient = [7u8; MAX_PLAINTEXT_LEN];
+
+        let blob = encrypt_recipient(&public_key, &recipient).unwrap();
+
+        assert_eq!(blob.len(), MAX_BLOB_LEN);
+        assert_eq!(decrypt_recipient(&secret_key, &blob).unwrap(), recipient);
+    }
+
+    #[test]
+    fn wrong_key_fails_to_decrypt() {
+        let (_, public_key) = generate_provider_keypair();
+        let (other_secret_key, _) = generate_provider_keypair();
+
+        let blob = encrypt_recipient(&public_key, b"agent-1234").unwrap();
+
+        assert_eq!(
+            decrypt_recipient(&other_secret_key, &blob),
+            Err(RecipientCryptoError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn tampered_blob_fails_to_decrypt() {
+        let (secret_key, public_key) = generate_provider_keypair();
+        let mut blob = encrypt_recipient(&public_key, b"agent-1234").unwrap();
+        let last = blob.len() - 1;
+        blob[last] ^= 1;
+
+        assert_eq!(
+            decrypt_recipient(&secret_key, &blob),
+            Err(RecipientCryptoError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_recipient_and_malformed_blob() {
+        let (secret_key, public_key) = generate_provider_keypair();
+
+        assert_eq!(
+            encrypt_recipient(&public_key, &[0u8; MAX_PLAINTEXT_LEN + 1]),
+            Err(RecipientCryptoError::PlaintextTooLong)
+        );
+        assert_eq!(
+            decrypt_recipient(&secret_key, &[0u8; KEY_SIZE + NONCE_SIZE]),
+            Err(RecipientCryptoError::MalformedBlob)
+        );
+    }
+}